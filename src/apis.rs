@@ -3,18 +3,45 @@ use jelly_model::store::{
     api::{anchor::ApiDataParsedId, ApiData},
     code::{anchor::CodeDataParsedId, CodeData},
     combined::{anchor::CombinedParsedId, Combined},
-    dapp::{access::DappVerified, anchor::DappParsedId, Dapp},
+    dapp::{
+        access::{DappAccess, DappVerified},
+        anchor::DappParsedId,
+        Dapp,
+    },
     publisher::{anchor::PublisherParsedId, Publisher},
 };
 
 use crate::stable::*;
+use crate::types::{
+    AnchorItem, AnchorValue, BalanceRequirement, ChangesPage, CombinedRecord, CounterKind, DappAccessRules, DappFrozen,
+    DappRecord, EntityKind, ExportCursor, ExportPage, ExportProgress, HealthView, ImportProgress, PaymentConfig,
+    PublisherKey, PublisherUsageView, QuotaLimit, RankItem, RankPeriod, ReplicaOp, ReplicaStatus, Replication,
+    ResolveCursor, ResolvedDapp, SignedToken, StorageStats, UploadLimits, UploadPayment, UploadReceipt,
+};
 
 // ================== init ==================
 
 #[ic_cdk::init]
 fn initial() {
     let deployer = ic_cdk::caller();
-    with_mut_state(|s| s.admin_add(deployer))
+    with_mut_state(|s| s.admin_add(deployer));
+    start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    start_timers(); // Timers are cleared by the upgrade
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(REPLICATION_INTERVAL_SECS), || {
+        ic_cdk::spawn(push_replicas())
+    });
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(CHANGES_FLUSH_INTERVAL_SECS), || {
+        with_mut_state(|s| s.changes_flush());
+    });
+    #[cfg(feature = "index")]
+    crate::index::start_index_timers();
 }
 
 // ================== admin ==================
@@ -36,25 +63,221 @@ fn admin_query() -> Vec<Principal> {
 
 #[ic_cdk::update(guard = "must_be_admin")]
 fn publisher_update(publisher_json: String) {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
     #[allow(clippy::unwrap_used)] // ? SAFETY
     let publisher: Publisher = serde_json::from_str(&publisher_json).unwrap();
     with_mut_state(|s| s.publisher_update(publisher))
 }
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "must_be_live")]
 fn publisher_query(anchor: String) -> Option<String> {
     let id: PublisherParsedId = anchor.as_str().try_into().ok()?;
     with_state(|s| s.publisher_query(id)).and_then(|publisher| serde_json::to_string(&publisher).ok())
 }
+#[ic_cdk::update(guard = "must_be_writable")]
+fn publisher_key_update(anchor: String, key: Option<PublisherKey>) -> Result<(), String> {
+    let id: PublisherParsedId = anchor.as_str().try_into()?;
+    let caller = ic_cdk::caller();
+    with_mut_state(|s| s.publisher_key_update(id, key, &caller))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn publisher_key_query(anchor: String) -> Option<PublisherKey> {
+    let id: PublisherParsedId = anchor.as_str().try_into().ok()?;
+    with_state(|s| s.publisher_key_query(id))
+}
+#[ic_cdk::update(guard = "must_be_writable")]
+fn publisher_controller_update(anchor: String, controller: Option<Principal>) -> Result<(), String> {
+    let id: PublisherParsedId = anchor.as_str().try_into()?;
+    let caller = ic_cdk::caller();
+    with_mut_state(|s| s.publisher_controller_update(id, controller, &caller))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn publisher_controller_query(anchor: String) -> Option<Principal> {
+    let id: PublisherParsedId = anchor.as_str().try_into().ok()?;
+    with_state(|s| s.publisher_controller_query(id))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn publisher_quota_update(anchor: String, quota: Option<QuotaLimit>) -> Result<(), String> {
+    must_be_writable()?;
+    let id: PublisherParsedId = anchor.as_str().try_into()?;
+    with_mut_state(|s| s.publisher_quota_update(id, quota))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn quota_default_update(quota: QuotaLimit) {
+    with_mut_state(|s| s.quota_default_update(quota))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn publisher_usage(anchor: String) -> Option<PublisherUsageView> {
+    let id: PublisherParsedId = anchor.as_str().try_into().ok()?;
+    with_state(|s| s.publisher_usage(id))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn payment_config_update(payment: PaymentConfig) {
+    with_mut_state(|s| s.payment_config_update(payment))
+}
+#[ic_cdk::query]
+fn payment_config_query() -> PaymentConfig {
+    with_state(|s| s.payment_config())
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn upload_receipt_query(id: u64) -> Option<UploadReceipt> {
+    let caller = ic_cdk::caller();
+    with_state(|s| s.upload_receipt_query(id, &caller))
+}
+#[ic_cdk::query(guard = "must_be_admin")]
+fn upload_receipts_query(offset: u64, limit: u64) -> Vec<UploadReceipt> {
+    with_state(|s| s.upload_receipts_query(offset, limit))
+}
+
+// ================== publisher writes ==================
+
+#[ic_cdk::update(guard = "must_be_writable")]
+async fn publisher_code_update(publisher: String, code_json: String) -> Result<(), String> {
+    let parsed: PublisherParsedId = publisher.as_str().try_into()?;
+    let code: CodeData = serde_json::from_str(&code_json).map_err(|err| format!("wrong code: {err}"))?;
+    let anchor = code.anchor.as_ref().to_string();
+    let caller = ic_cdk::caller();
+    let bytes = match with_state(|s| s.publisher_code_check(&parsed, &code, &caller))? {
+        Some(bytes) => bytes,
+        None => return Ok(()), // The same code is stored, nothing is charged
+    };
+    paid_upload(publisher, anchor, bytes, move |s| {
+        s.publisher_code_update(parsed, code, &caller)
+    })
+    .await
+}
+#[ic_cdk::update(guard = "must_be_writable")]
+async fn publisher_api_update(publisher: String, api_json: String) -> Result<(), String> {
+    let parsed: PublisherParsedId = publisher.as_str().try_into()?;
+    let api: ApiData = serde_json::from_str(&api_json).map_err(|err| format!("wrong api: {err}"))?;
+    let anchor = api.anchor.as_ref().to_string();
+    let caller = ic_cdk::caller();
+    let bytes = match with_state(|s| s.publisher_apis_check(&parsed, &api, &caller))? {
+        Some(bytes) => bytes,
+        None => return Ok(()), // The same api is stored, nothing is charged
+    };
+    paid_upload(publisher, anchor, bytes, move |s| {
+        s.publisher_apis_update(parsed, api, &caller)
+    })
+    .await
+}
+#[ic_cdk::update(guard = "must_be_writable")]
+async fn publisher_combined_update(publisher: String, combined_json: String) -> Result<(), String> {
+    let parsed: PublisherParsedId = publisher.as_str().try_into()?;
+    let combined: Combined =
+        serde_json::from_str(&combined_json).map_err(|err| format!("wrong combined: {err}"))?;
+    let anchor = combined.anchor.as_ref().to_string();
+    let caller = ic_cdk::caller();
+    let bytes = match with_state(|s| s.publisher_combined_check(&parsed, &combined, &caller))? {
+        Some(bytes) => bytes,
+        None => return Ok(()), // The same combined is stored, nothing is charged
+    };
+    paid_upload(publisher, anchor, bytes, move |s| {
+        s.publisher_combined_update(parsed, combined, &caller)
+    })
+    .await
+}
+#[ic_cdk::update(guard = "must_be_writable")]
+async fn publisher_dapp_update(publisher: String, dapp_json: String) -> Result<(), String> {
+    let parsed: PublisherParsedId = publisher.as_str().try_into()?;
+    let mut dapp: Dapp = serde_json::from_str(&dapp_json).map_err(|err| format!("wrong dapp: {err}"))?;
+    let anchor = dapp.id.as_ref().to_string();
+    let caller = ic_cdk::caller();
+    let bytes = match with_state(|s| s.publisher_dapp_check(&parsed, &mut dapp, &caller))? {
+        Some(bytes) => bytes,
+        None => return Ok(()),
+    };
+    paid_upload(publisher, anchor, bytes, move |s| {
+        s.publisher_dapp_update(parsed, dapp, &caller).map(|()| true)
+    })
+    .await
+}
+
+/// Charge the caller by the stored size of the upload, then store it and record the receipt.
+/// The upload must be checked before, so the rejected upload is not charged.
+/// The store returns false if the same item is stored meanwhile, nothing is charged then.
+/// The attached cycles are only accepted if the upload is stored,
+/// the ICRC-2 transfer happens before, so it is refunded if the state is changed while transferring
+async fn paid_upload<F>(publisher: String, anchor: String, bytes: u64, store: F) -> Result<(), String>
+where
+    F: FnOnce(&mut State) -> Result<bool, String>,
+{
+    let caller = ic_cdk::caller();
+    let config = with_state(|s| s.payment_config());
+
+    let payment = if config.is_free() {
+        UploadPayment::Free
+    } else if 0 < ic_cdk::api::call::msg_cycles_available128() {
+        let per_byte = config.cycles_per_byte.ok_or("cycles are not accepted")?;
+        let cycles = per_byte.saturating_mul(bytes as u128);
+        let available = ic_cdk::api::call::msg_cycles_available128();
+        if available < cycles {
+            return Err(format!("cycles are not enough: {available} < {cycles}"));
+        }
+        UploadPayment::Cycles(cycles)
+    } else if let Some(price) = config.icrc2 {
+        let amount = price.tokens_per_byte.saturating_mul(bytes as u128);
+        let block = crate::icrc::transfer_from(price.ledger, caller, ic_cdk::id(), amount).await?;
+        UploadPayment::Icrc2 {
+            ledger: price.ledger,
+            amount,
+            block,
+        }
+    } else {
+        return Err("payment is required: attach cycles".into());
+    };
+
+    // The state may be frozen while transferring
+    let result = must_be_writable().and_then(|()| with_mut_state(store));
+    if let (Ok(true), UploadPayment::Cycles(cycles)) = (&result, &payment) {
+        ic_cdk::api::call::msg_cycles_accept128(*cycles);
+    }
+
+    let refund = match (&result, &payment) {
+        (Ok(true), _) => None,
+        (_, UploadPayment::Icrc2 { ledger, amount, .. }) => Some((*ledger, *amount)),
+        _ => None,
+    };
+    if matches!(result, Ok(true)) || refund.is_some() {
+        let receipt = UploadReceipt {
+            id: 0,
+            publisher,
+            anchor,
+            caller,
+            bytes,
+            payment,
+            created: 0,
+            error: match &result {
+                Ok(true) => None,
+                Ok(false) => Some("the same item is stored".into()),
+                Err(err) => Some(err.clone()),
+            },
+            refund: None,
+        };
+        // The receipt is recorded before refunding, so the failed refund can be found.
+        // It is pending while the snapshot is frozen, and recorded after it is unfrozen
+        let id = with_mut_state(|s| s.upload_receipt_insert(receipt));
+        if let Some((ledger, amount)) = refund {
+            let refund = crate::icrc::transfer(ledger, caller, amount).await;
+            with_mut_state(|s| s.upload_receipt_refunded(id, refund));
+        }
+    }
+    result.map(|_| ())
+}
 
 // ================== code ==================
 
 #[ic_cdk::update(guard = "must_be_admin")]
 fn code_update(code_json: String) {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
     #[allow(clippy::unwrap_used)] // ? SAFETY
     let code: CodeData = serde_json::from_str(&code_json).unwrap();
     with_mut_state(|s| s.code_update(code))
 }
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "must_be_live")]
 fn code_query(anchor: String) -> Option<String> {
     let id: CodeDataParsedId = anchor.as_str().try_into().ok()?;
     with_state(|s| s.code_query(id)).and_then(|code| serde_json::to_string(&code).ok())
@@ -64,11 +287,14 @@ fn code_query(anchor: String) -> Option<String> {
 
 #[ic_cdk::update(guard = "must_be_admin")]
 fn api_update(api_json: String) {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
     #[allow(clippy::unwrap_used)] // ? SAFETY
     let api: ApiData = serde_json::from_str(&api_json).unwrap();
     with_mut_state(|s| s.apis_update(api))
 }
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "must_be_live")]
 fn api_query(anchor: String) -> Option<String> {
     let id: ApiDataParsedId = anchor.as_str().try_into().ok()?;
     with_state(|s| s.apis_query(id)).and_then(|api| serde_json::to_string(&api).ok())
@@ -78,18 +304,21 @@ fn api_query(anchor: String) -> Option<String> {
 
 #[ic_cdk::update(guard = "must_be_admin")]
 fn combined_update(combined_json: String) {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
     #[allow(clippy::unwrap_used)] // ? SAFETY
     let combined: Combined = serde_json::from_str(&combined_json).unwrap();
     with_mut_state(|s| s.combined_update(combined))
 }
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "must_be_writable")]
 fn combined_increment_called(anchor: String) {
     let id = anchor.as_str().try_into();
     if let Ok(id) = id {
         let _ = with_mut_state(|s| s.combined_increment_called(id));
     }
 }
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "must_be_live")]
 fn combined_query(anchor: String) -> Option<String> {
     let id: CombinedParsedId = anchor.as_str().try_into().ok()?;
     with_state(|s| s.combined_query(id)).and_then(|combined| serde_json::to_string(&combined).ok())
@@ -99,23 +328,53 @@ fn combined_query(anchor: String) -> Option<String> {
 
 #[ic_cdk::update(guard = "must_be_admin")]
 fn dapp_update(dapp_json: String) {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
     #[allow(clippy::unwrap_used)] // ? SAFETY
     let dapp: Dapp = serde_json::from_str(&dapp_json).unwrap();
     with_mut_state(|s| s.dapp_update(dapp))
 }
 #[ic_cdk::update(guard = "must_be_admin")]
 fn dapp_increment_called_by_admin(anchor: String) {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
     let id: Result<DappParsedId, _> = anchor.as_str().try_into();
     if let Ok(id) = id {
         let _ = with_mut_state(|s| s.dapp_increment_called_by_admin(id));
     }
 }
 #[ic_cdk::update(guard = "must_be_admin")]
-fn dapp_update_collected(anchor: String, collected: u64) {
-    let id: Result<DappParsedId, _> = anchor.as_str().try_into();
-    if let Ok(id) = id {
-        let _ = with_mut_state(|s| s.dapp_update_collected(id, collected));
-    }
+fn dapp_owner_update(anchor: String, publisher: Option<String>) -> Result<(), String> {
+    must_be_writable()?;
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let publisher: Option<PublisherParsedId> = publisher.map(|p| p.as_str().try_into()).transpose()?;
+    with_mut_state(|s| s.dapp_owner_update(id, publisher))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn dapp_access_update(anchor: String, access_json: String) -> Result<(), String> {
+    must_be_writable()?;
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let access: DappAccess = serde_json::from_str(&access_json).map_err(|err| format!("wrong access: {err}"))?;
+    with_mut_state(|s| s.dapp_access_update(id, access))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn dapp_freeze(anchor: String, reason: String) -> Result<(), String> {
+    must_be_writable()?;
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let moderator = ic_cdk::caller();
+    with_mut_state(|s| s.dapp_freeze(id, reason, moderator))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn dapp_unfreeze(anchor: String) -> Result<(), String> {
+    must_be_writable()?;
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    with_mut_state(|s| s.dapp_unfreeze(id))
+}
+#[ic_cdk::query(guard = "must_be_admin")]
+fn dapp_query_frozen(offset: u64, limit: u64) -> Vec<DappFrozen> {
+    with_state(|s| s.dapp_query_frozen(offset, limit))
 }
 #[ic_cdk::query(guard = "must_be_admin")]
 fn dapp_query_by_admin(anchor: String) -> Result<String, String> {
@@ -125,15 +384,27 @@ fn dapp_query_by_admin(anchor: String) -> Result<String, String> {
 }
 
 // get access
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "must_be_live")]
 fn dapp_query_access(anchor: String) -> Result<String, String> {
     let id: DappParsedId = anchor.as_str().try_into()?;
     let access = with_state(|s| s.dapp_query_access(id))?;
     let access = serde_json::to_string(&access).map_err(|err| format!("serialize access failed: {err}"))?;
     Ok(access)
 }
-#[ic_cdk::update]
-fn dapp_increment_called_by_token(anchor: String, verified: Option<String>) {
+#[ic_cdk::update(guard = "must_be_writable")]
+fn dapp_access_rules_update(anchor: String, rules: DappAccessRules) -> Result<(), String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let caller = ic_cdk::caller();
+    with_mut_state(|s| s.dapp_access_rules_update(id, rules, &caller))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn dapp_access_rules_query(anchor: String) -> Result<DappAccessRules, String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let caller = ic_cdk::caller();
+    with_state(|s| s.dapp_access_rules_query(id, &caller))
+}
+#[ic_cdk::update(guard = "must_be_writable")]
+async fn dapp_increment_called_by_token(anchor: String, verified: Option<String>, token: Option<SignedToken>) {
     let id: Result<DappParsedId, _> = anchor.as_str().try_into();
     let verified = match verified {
         Some(verified) => {
@@ -146,25 +417,424 @@ fn dapp_increment_called_by_token(anchor: String, verified: Option<String>) {
         None => None,
     };
     if let Ok(id) = id {
-        let _ = with_mut_state(|s| s.dapp_increment_called_by_token(id, verified));
+        let caller = ic_cdk::caller();
+        let requirements = match with_state(|s| {
+            s.dapp_access_requirements(&id, verified.as_ref(), token.as_ref(), &caller)
+        }) {
+            Ok(requirements) => requirements,
+            Err(_) => return,
+        };
+        if check_access_requirements(caller, requirements).await.is_err() || must_be_writable().is_err() {
+            return;
+        }
+        let _ = with_mut_state(|s| s.dapp_increment_called_by_token(id, verified, token.as_ref(), &caller));
     }
 }
-#[ic_cdk::query]
-fn dapp_query_by_token(anchor: String, verified: Option<String>) -> Result<String, String> {
+#[ic_cdk::query(guard = "must_be_live")]
+fn dapp_query_by_token(anchor: String, verified: Option<String>, token: Option<SignedToken>) -> Result<String, String> {
     let id: DappParsedId = anchor.as_str().try_into()?;
-    let verified = match verified {
-        Some(verified) => {
-            let verified: DappVerified = match serde_json::from_str(&verified) {
-                Ok(verified) => verified,
-                Err(err) => return Err(format!("wrong verified: {err}")),
-            };
-            Some(verified)
-        }
-        None => None,
-    };
-    let dapp = with_state(|s| s.dapp_query_by_token(id, verified))?;
+    let verified = parse_verified(verified)?;
+    let caller = ic_cdk::caller();
+    let requirements = with_state(|s| s.dapp_access_requirements(&id, verified.as_ref(), token.as_ref(), &caller))?;
+    if !requirements.is_empty() {
+        return Err("access requires the balance of the caller: use dapp_query_by_token_composite".into());
+    }
+    let dapp = with_state(|s| s.dapp_query_by_token(id, verified, token.as_ref(), &caller))?;
+    serde_json::to_string(&dapp).map_err(|e| format!("serialize failed: {e}"))
+}
+/// Same as dapp_query_by_token, and check the balance of the caller by the ledgers on the same subnet
+#[ic_cdk::query(composite = true, guard = "must_be_live")]
+async fn dapp_query_by_token_composite(
+    anchor: String,
+    verified: Option<String>,
+    token: Option<SignedToken>,
+) -> Result<String, String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let verified = parse_verified(verified)?;
+    let caller = ic_cdk::caller();
+    let requirements = with_state(|s| s.dapp_access_requirements(&id, verified.as_ref(), token.as_ref(), &caller))?;
+    check_access_requirements(caller, requirements).await?;
+    let dapp = with_state(|s| s.dapp_query_by_token(id, verified, token.as_ref(), &caller))?;
     serde_json::to_string(&dapp).map_err(|e| format!("serialize failed: {e}"))
 }
+#[ic_cdk::update(guard = "must_be_writable")]
+fn dapp_collect(anchor: String) -> Result<u64, String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let caller = ic_cdk::caller();
+    with_mut_state(|s| s.dapp_collect(id, &caller))
+}
+#[ic_cdk::update(guard = "must_be_writable")]
+fn dapp_uncollect(anchor: String) -> Result<u64, String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let caller = ic_cdk::caller();
+    with_mut_state(|s| s.dapp_uncollect(id, &caller))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn my_collections(offset: u64, limit: u64) -> Vec<String> {
+    let caller = ic_cdk::caller();
+    with_state(|s| s.my_collections(&caller, offset, limit))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn dapp_unique_users(anchor: String, days: u32) -> Result<u64, String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    with_state(|s| s.dapp_unique_users(id, days))
+}
+
+fn parse_verified(verified: Option<String>) -> Result<Option<DappVerified>, String> {
+    verified
+        .map(|verified| serde_json::from_str(&verified).map_err(|err| format!("wrong verified: {err}")))
+        .transpose()
+}
+
+/// Check the requirements by calling other canisters.
+/// In composite queries, only the canisters on the same subnet can be called
+async fn check_access_requirements(caller: Principal, requirements: Vec<BalanceRequirement>) -> Result<(), String> {
+    for requirement in requirements {
+        let balance = crate::icrc::balance_of(requirement.ledger, caller).await?;
+        if balance < candid::Nat::from(requirement.min) {
+            return Err(format!("access is deny: balance is less than {}", requirement.min));
+        }
+    }
+    Ok(())
+}
+
+// ================== canister consumers ==================
+
+#[ic_cdk::update(guard = "must_be_admin")]
+fn trusted_canisters_update(canisters: Vec<Principal>) {
+    with_mut_state(|s| s.trusted_canisters_update(canisters))
+}
+#[ic_cdk::query(guard = "must_be_admin")]
+fn trusted_canisters_query() -> Vec<Principal> {
+    with_state(|s| s.trusted_canisters())
+}
+/// Typed batch query for other canisters, the trusted canisters bypass the access checks
+#[ic_cdk::query(composite = true, guard = "must_be_live")]
+async fn dapp_get_for_canister(anchors: Vec<String>) -> Vec<Result<DappRecord, String>> {
+    let caller = ic_cdk::caller();
+    let trusted = with_state(|s| s.is_trusted_canister(&caller));
+    let mut records = vec![];
+    for anchor in anchors.into_iter().take(MAX_CANISTER_BATCH) {
+        records.push(inner_dapp_get_for_canister(&anchor, caller, trusted).await);
+    }
+    records
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn combined_get_for_canister(anchors: Vec<String>) -> Vec<Result<CombinedRecord, String>> {
+    with_state(|s| {
+        anchors
+            .into_iter()
+            .take(MAX_CANISTER_BATCH)
+            .map(|anchor| {
+                let id: CombinedParsedId = anchor.as_str().try_into()?;
+                s.combined_get_for_canister(id)
+            })
+            .collect()
+    })
+}
+
+async fn inner_dapp_get_for_canister(anchor: &str, caller: Principal, trusted: bool) -> Result<DappRecord, String> {
+    let id: DappParsedId = anchor.try_into()?;
+    if !trusted {
+        let requirements = with_state(|s| s.dapp_access_requirements(&id, None, None, &caller))?;
+        check_access_requirements(caller, requirements).await?;
+    }
+    with_state(|s| s.dapp_get_for_canister(id))
+}
+
+// ================== batch ==================
+
+/// The dapp and every item it depends on in one response, continued by next if it is too large
+#[ic_cdk::query(composite = true, guard = "must_be_live")]
+async fn resolve_dapp(
+    anchor: String,
+    verified: Option<String>,
+    token: Option<SignedToken>,
+    next: Option<ResolveCursor>,
+) -> Result<ResolvedDapp, String> {
+    let id: DappParsedId = anchor.as_str().try_into()?;
+    let verified = parse_verified(verified)?;
+    let caller = ic_cdk::caller();
+    let requirements = with_state(|s| s.dapp_access_requirements(&id, verified.as_ref(), token.as_ref(), &caller))?;
+    check_access_requirements(caller, requirements).await?;
+    with_state(|s| s.resolve_dapp(&anchor, verified, token.as_ref(), &caller, next))
+}
+/// The uploaded items of the anchors of any kind
+#[ic_cdk::query(composite = true, guard = "must_be_live")]
+async fn batch_query(anchors: Vec<String>) -> Vec<Result<AnchorItem, String>> {
+    let caller = ic_cdk::caller();
+    let mut items = vec![];
+    for anchor in anchors.into_iter().take(MAX_BATCH_QUERY) {
+        items.push(inner_batch_query(&anchor, caller).await);
+    }
+    items
+}
+
+/// Detect the kind of the anchor and query its item, handy for the explorer and the deep links
+#[ic_cdk::query(composite = true, guard = "must_be_live")]
+async fn query_anchor(
+    anchor: String,
+    verified: Option<String>,
+    token: Option<SignedToken>,
+) -> Result<AnchorValue, String> {
+    let verified = parse_verified(verified)?;
+    let caller = ic_cdk::caller();
+    check_anchor_requirements(&anchor, verified.as_ref(), token.as_ref(), caller).await?;
+    with_state(|s| s.query_anchor(&anchor, verified, token.as_ref(), &caller))
+}
+
+async fn inner_batch_query(anchor: &str, caller: Principal) -> Result<AnchorItem, String> {
+    check_anchor_requirements(anchor, None, None, caller).await?;
+    with_state(|s| s.anchor_item(anchor, None, None, &caller))
+}
+
+/// The balance requirements of the dapp anchor, other kinds are not checked
+async fn check_anchor_requirements(
+    anchor: &str,
+    verified: Option<&DappVerified>,
+    token: Option<&SignedToken>,
+    caller: Principal,
+) -> Result<(), String> {
+    if anchor_kind(anchor)? == EntityKind::Dapp {
+        let id: DappParsedId = anchor.try_into()?;
+        let requirements = with_state(|s| s.dapp_access_requirements(&id, verified, token, &caller))?;
+        check_access_requirements(caller, requirements).await?;
+    }
+    Ok(())
+}
+
+// ================== counters ==================
+
+#[ic_cdk::update(guard = "must_be_admin")]
+fn counters_apply_batch(items: Vec<(String, CounterKind, u64)>) -> Vec<Result<u64, String>> {
+    if let Err(err) = must_be_writable() {
+        ic_cdk::trap(&err);
+    }
+    with_mut_state(|s| s.counters_apply_batch(items))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn counters_set(anchor: String, kind: CounterKind, value: u64) -> Result<(), String> {
+    must_be_writable()?;
+    with_mut_state(|s| s.counters_set(&anchor, kind, value))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn counters_reset(anchor: String, kind: CounterKind) -> Result<(), String> {
+    must_be_writable()?;
+    with_mut_state(|s| s.counters_reset(&anchor, kind))
+}
+
+// ================== ranks ==================
+
+#[ic_cdk::query(guard = "must_be_live")]
+fn top_dapps(metric: CounterKind, period: RankPeriod, offset: u64, limit: u64) -> Result<Vec<RankItem>, String> {
+    with_state(|s| s.top_dapps(metric, period, offset, limit))
+}
+#[ic_cdk::query(guard = "must_be_live")]
+fn top_combined(period: RankPeriod, offset: u64, limit: u64) -> Vec<RankItem> {
+    with_state(|s| s.top_combined(period, offset, limit))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn rank_prune(before_day: u32) -> u64 {
+    with_mut_state(|s| s.rank_prune(before_day))
+}
+
+// ================== replication ==================
+
+const REPLICATION_INTERVAL_SECS: u64 = 10;
+
+thread_local! {
+    static PUSHING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[ic_cdk::update(guard = "must_be_admin")]
+fn replication_update(replication: Replication) {
+    with_mut_state(|s| s.replication_update(replication))
+}
+#[ic_cdk::query(guard = "must_be_admin")]
+fn replication_status() -> Vec<(Principal, ReplicaStatus)> {
+    with_state(|s| s.replication_status())
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+async fn replicate_push() {
+    push_replicas().await
+}
+/// Called by the primary
+#[ic_cdk::update(guard = "must_be_live")]
+fn replicate_apply(from: u64, ops: Vec<ReplicaOp>) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    with_mut_state(|s| s.replicate_apply(&caller, from, ops))
+}
+
+/// Clear the flag when the round ends, the future is also dropped if a callback traps
+struct PushingGuard;
+
+impl Drop for PushingGuard {
+    fn drop(&mut self) {
+        PUSHING.with(|pushing| pushing.set(false));
+    }
+}
+
+/// Push the ops to the secondaries, the failed ones are pushed again in the next round
+async fn push_replicas() {
+    if PUSHING.with(|pushing| pushing.replace(true)) {
+        return; // The last round is not finished
+    }
+    let _guard = PushingGuard;
+    let batches = with_mut_state(|s| s.replication_batches());
+    for (secondary, from, ops) in batches {
+        let end = from + ops.len() as u64;
+        let result = ic_cdk::call::<_, (Result<u64, String>,)>(secondary, "replicate_apply", (from, ops))
+            .await
+            .map_err(|(code, message)| format!("push failed: {code:?} {message}"))
+            .and_then(|(result,)| result);
+        with_mut_state(|s| s.replication_pushed(secondary, end, result));
+    }
+}
+
+// ================== changes ==================
+
+const CHANGES_FLUSH_INTERVAL_SECS: u64 = 60;
+
+/// Indexers tail the change feed from the next of the last page
+#[ic_cdk::query(guard = "must_be_live")]
+fn changes_since(seq: u64, limit: u64) -> ChangesPage {
+    with_state(|s| s.changes_since(seq, limit))
+}
+
+// ================== snapshot ==================
+
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_export_begin() -> Result<(), String> {
+    with_mut_state(|s| s.state_export_begin())
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_export(cursor: Option<ExportCursor>) -> Result<ExportPage, String> {
+    with_mut_state(|s| s.state_export(cursor))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_export_end() {
+    with_mut_state(|s| s.state_export_end())
+}
+#[ic_cdk::query(guard = "must_be_admin")]
+fn state_export_status() -> ExportProgress {
+    with_state(|s| s.state_export_status())
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_wipe() -> Result<u64, String> {
+    with_mut_state(|s| s.state_wipe())
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_import_begin() -> Result<(), String> {
+    with_mut_state(|s| s.state_import_begin())
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_import(data: Vec<u8>, checksum: Vec<u8>) -> Result<(), String> {
+    with_mut_state(|s| s.state_import(data, checksum))
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn state_import_commit(checksum: Vec<u8>) -> Result<(), String> {
+    with_mut_state(|s| s.state_import_commit(checksum))
+}
+#[ic_cdk::query(guard = "must_be_admin")]
+fn state_import_status() -> ImportProgress {
+    with_state(|s| s.state_import_status())
+}
+
+// ================== inspect ==================
+
+/// Methods guarded by must_be_admin, kept in sync with the guards by the test in test.rs
+pub(crate) const ADMIN_METHODS: &[&str] = &[
+    "admin_add",
+    "admin_remove",
+    "admin_query",
+    "publisher_update",
+    "publisher_quota_update",
+    "quota_default_update",
+    "payment_config_update",
+    "upload_receipts_query",
+    "code_update",
+    "api_update",
+    "combined_update",
+    "dapp_update",
+    "dapp_increment_called_by_admin",
+    "dapp_owner_update",
+    "dapp_access_update",
+    "dapp_freeze",
+    "dapp_unfreeze",
+    "dapp_query_frozen",
+    "dapp_query_by_admin",
+    "counters_apply_batch",
+    "counters_set",
+    "counters_reset",
+    "rank_prune",
+    "upload_limits_update",
+    "max_payload_bytes_update",
+    "state_export_begin",
+    "state_export",
+    "state_export_end",
+    "state_export_status",
+    "state_wipe",
+    "state_import_begin",
+    "state_import",
+    "state_import_commit",
+    "state_import_status",
+    "replication_update",
+    "replication_status",
+    "replicate_push",
+    "trusted_canisters_update",
+    "trusted_canisters_query",
+    #[cfg(feature = "index")]
+    "index_settings_update",
+    #[cfg(feature = "index")]
+    "index_shard_add",
+    #[cfg(feature = "index")]
+    "index_shard_install",
+    #[cfg(feature = "index")]
+    "index_wasm_upload",
+    #[cfg(feature = "index")]
+    "index_allocate",
+    #[cfg(feature = "index")]
+    "index_refresh",
+];
+
+/// Methods that count the caller, anonymous is meaningless
+const NAMED_METHODS: &[&str] = &[
+    "combined_increment_called",
+    "dapp_increment_called_by_token",
+    "dapp_collect",
+    "dapp_uncollect",
+];
+
+/// Reject the ingress messages before they are executed and cost cycles.
+/// Inter-canister calls do not pass here, so the guards are still required
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let admin = ADMIN_METHODS.contains(&method.as_str());
+    if admin && must_be_admin().is_err() {
+        ic_cdk::trap("Permission is required");
+    }
+    if with_state(|s| s.importing()) && !admin {
+        ic_cdk::trap("snapshot is importing");
+    }
+    if NAMED_METHODS.contains(&method.as_str()) && ic_cdk::caller() == Principal::anonymous() {
+        ic_cdk::trap("anonymous caller is not allowed");
+    }
+    // The administrators upload the whole code, the limit is for the public methods
+    if !admin {
+        let size = ic_cdk::api::call::arg_data_raw_size() as u64;
+        let max = with_state(|s| s.max_payload_bytes());
+        if max < size {
+            ic_cdk::trap(&format!("payload is too large: {size} > {max}"));
+        }
+    }
+    ic_cdk::api::call::accept_message();
+}
+
+#[ic_cdk::update(guard = "must_be_admin")]
+fn max_payload_bytes_update(max: Option<u64>) {
+    with_mut_state(|s| s.max_payload_bytes_update(max))
+}
 
 // ================== common ==================
 
@@ -184,6 +854,19 @@ async fn canister_status() -> ic_cdk::api::management_canister::main::CanisterSt
     .0
 }
 
+#[ic_cdk::query]
+fn storage_stats() -> StorageStats {
+    with_state(|s| s.storage_stats())
+}
+#[ic_cdk::query]
+fn health() -> HealthView {
+    with_state(|s| s.health())
+}
+#[ic_cdk::update(guard = "must_be_admin")]
+fn upload_limits_update(limits: UploadLimits) {
+    with_mut_state(|s| s.upload_limits_update(limits))
+}
+
 #[ic_cdk::query]
 async fn whoami() -> Principal {
     ic_cdk::api::caller()
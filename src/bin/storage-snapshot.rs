@@ -0,0 +1,97 @@
+//! Offline tool to inspect the snapshots of the storage canister
+//!
+//! cargo run --features cli --bin storage-snapshot -- <FILE | --dump FILE> <COMMAND>
+//!
+//! The exported snapshot is made page by page:
+//!
+//! dfx canister call storage state_export_begin
+//! dfx canister call storage state_export '(null)' --output raw > reply
+//! storage-snapshot append FILE reply
+//!
+//! then state_export is called with the printed argument until the last page is appended
+
+use std::io::Write;
+
+use storage::offline::{cursor_argument, export_page, Snapshot};
+
+const USAGE: &str = "usage: storage-snapshot <FILE | --dump FILE> <COMMAND>
+       storage-snapshot append FILE REPLY
+append:
+    append the raw reply of state_export to the exported snapshot, print the argument of the next page
+commands:
+    list [MAP]      entries of every map, or the items of the map
+    dapp ANCHOR     the dapp with its counters and access rules
+    diff <FILE | --dump FILE>
+                    changes from this snapshot to the other one
+    check           integrity checks";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    if let Err(err) = run(&mut args) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &mut impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or(USAGE)?;
+    if path == "append" {
+        return append(args);
+    }
+    let snapshot = read_path(path, args)?;
+    let lines = match args.next().as_deref() {
+        Some("list") => snapshot.list(args.next().as_deref())?,
+        Some("dapp") => vec![snapshot.dapp(&args.next().ok_or(USAGE)?)?],
+        Some("diff") => snapshot.diff(&read(args)?),
+        Some("check") => {
+            let problems = snapshot.check();
+            if !problems.is_empty() {
+                return Err(format!(
+                    "{} problems are found:\n{}",
+                    problems.len(),
+                    problems.join("\n")
+                ));
+            }
+            vec![format!("ok, checksum: {}", snapshot.checksum())]
+        }
+        _ => return Err(USAGE.into()),
+    };
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Append the page to the file, the file is created by the first page
+fn append(args: &mut impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or(USAGE)?;
+    let reply = args.next().ok_or(USAGE)?;
+    let reply = std::fs::read_to_string(&reply).map_err(|err| format!("read {reply} failed: {err}"))?;
+    let (page, next) = export_page(&reply)?;
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&page))
+        .map_err(|err| format!("write {path} failed: {err}"))?;
+    match next {
+        Some(next) => println!("{}", cursor_argument(&next)),
+        None => println!("the last page is appended, check it: storage-snapshot {path} check"),
+    }
+    Ok(())
+}
+
+/// The exported snapshot, or the raw stable memory dump after --dump
+fn read(args: &mut impl Iterator<Item = String>) -> Result<Snapshot, String> {
+    let path = args.next().ok_or(USAGE)?;
+    read_path(path, args)
+}
+fn read_path(path: String, args: &mut impl Iterator<Item = String>) -> Result<Snapshot, String> {
+    if path == "--dump" {
+        let path = args.next().ok_or(USAGE)?;
+        let bytes = std::fs::read(&path).map_err(|err| format!("read {path} failed: {err}"))?;
+        return Ok(Snapshot::from_dump(bytes));
+    }
+    let bytes = std::fs::read(&path).map_err(|err| format!("read {path} failed: {err}"))?;
+    Snapshot::from_export(&bytes)
+}
@@ -0,0 +1,115 @@
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+
+/// ICRC-1 account
+#[derive(CandidType, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// ICRC-1 balance of the account
+pub async fn balance_of(ledger: Principal, owner: Principal) -> Result<Nat, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (Account::from(owner),))
+        .await
+        .map_err(|(code, message)| format!("query balance failed: {code:?} {message}"))?;
+    Ok(balance)
+}
+
+/// ICRC-2 transfer approved by the owner, the block index is returned
+pub async fn transfer_from(ledger: Principal, from: Principal, to: Principal, amount: u128) -> Result<u64, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.into(),
+        to: to.into(),
+        amount: amount.into(),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, message)| format!("transfer failed: {code:?} {message}"))?;
+    let block = result.map_err(|err| format!("transfer failed: {err:?}"))?;
+    block_index(block)
+}
+
+/// ICRC-1 transfer from the canister, the fee is paid by the canister
+pub async fn transfer(ledger: Principal, to: Principal, amount: u128) -> Result<u64, String> {
+    let args = TransferArgs {
+        from_subaccount: None,
+        to: to.into(),
+        amount: amount.into(),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, message)| format!("transfer failed: {code:?} {message}"))?;
+    let block = result.map_err(|err| format!("transfer failed: {err:?}"))?;
+    block_index(block)
+}
+
+fn block_index(block: Nat) -> Result<u64, String> {
+    match block.0.to_u64_digits().as_slice() {
+        [] => Ok(0),
+        [block] => Ok(*block),
+        _ => Err(format!("block index is too large: {block}")),
+    }
+}
@@ -0,0 +1,406 @@
+use std::cell::RefCell;
+
+use candid::Principal;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+};
+use jelly_model::store::{
+    api::anchor::ApiDataParsedId, code::anchor::CodeDataParsedId, combined::anchor::CombinedParsedId,
+    dapp::anchor::DappParsedId, publisher::anchor::PublisherParsedId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::stable::{get_virtual_memory, must_be_admin};
+use crate::types::*;
+
+/// The index routes the anchors to the storage shards.
+/// The shards record the anchors of the new items, the anchors not recorded are routed by parsing them
+#[derive(Serialize, Deserialize)]
+pub struct IndexState {
+    #[serde(skip, default = "init_index_settings_data")]
+    settings: StableCell<IndexSettings>,
+    #[serde(skip, default = "init_shards_data")]
+    shards: StableBTreeMap<Principal, ShardInfo>,
+    #[serde(skip, default = "init_anchors_data")]
+    anchors: StableBTreeMap<String, Principal>, // The shard holds the anchor
+    #[serde(skip, default = "init_wasm_data")]
+    wasm: StableBTreeMap<u32, Vec<u8>>, // Chunks of the wasm of the storage canister
+}
+
+impl Default for IndexState {
+    fn default() -> Self {
+        Self {
+            settings: init_index_settings_data(),
+            shards: init_shards_data(),
+            anchors: init_anchors_data(),
+            wasm: init_wasm_data(),
+        }
+    }
+}
+
+thread_local! {
+    static INDEX_STATE: RefCell<IndexState> = RefCell::default();
+    static SPAWNING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+fn with_index_state<F, R>(callback: F) -> R
+where
+    F: FnOnce(&IndexState) -> R,
+{
+    INDEX_STATE.with(|state| callback(&state.borrow()))
+}
+
+fn with_mut_index_state<F, R>(callback: F) -> R
+where
+    F: FnOnce(&mut IndexState) -> R,
+{
+    INDEX_STATE.with(|state| callback(&mut state.borrow_mut()))
+}
+
+const MEMORY_ID_INDEX_SETTINGS: u8 = 80; // Index
+const MEMORY_ID_SHARDS: u8 = 81; // Index
+const MEMORY_ID_ANCHORS: u8 = 82; // Index
+const MEMORY_ID_WASM: u8 = 83; // Index
+
+const REFRESH_INTERVAL_SECS: u64 = 10 * 60; // Refresh the usage of the shards
+const MAX_LOOKUP_ANCHORS: usize = 100; // Max anchors of one lookup
+
+fn init_index_settings_data() -> StableCell<IndexSettings> {
+    #[allow(clippy::expect_used)] // ? SAFETY
+    StableCell::init(get_virtual_memory(MEMORY_ID_INDEX_SETTINGS), Default::default()).expect("failed to initialize")
+}
+fn init_shards_data() -> StableBTreeMap<Principal, ShardInfo> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_SHARDS))
+}
+fn init_anchors_data() -> StableBTreeMap<String, Principal> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_ANCHORS))
+}
+fn init_wasm_data() -> StableBTreeMap<u32, Vec<u8>> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_WASM))
+}
+
+impl Storable for IndexSettings {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ShardInfo {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The anchor is issued for the canister
+fn anchor_of_canister(anchor: &str, canister: &Principal) -> bool {
+    if let Ok(id) = PublisherParsedId::try_from(anchor) {
+        return id.check_canister_id(canister).is_ok();
+    }
+    if let Ok(id) = CodeDataParsedId::try_from(anchor) {
+        return id.check_canister_id(canister).is_ok();
+    }
+    if let Ok(id) = ApiDataParsedId::try_from(anchor) {
+        return id.check_canister_id(canister).is_ok();
+    }
+    if let Ok(id) = CombinedParsedId::try_from(anchor) {
+        return id.check_canister_id(canister).is_ok();
+    }
+    if let Ok(id) = DappParsedId::try_from(anchor) {
+        return id.check_canister_id(canister).is_ok();
+    }
+    false
+}
+
+impl IndexState {
+    fn settings_update(&mut self, settings: IndexSettings) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
+    }
+    /// The spawned shard is installing until the wasm is installed and the administrators are added
+    fn shard_add(&mut self, canister: Principal, installing: bool) {
+        if !self.shards.contains_key(&canister) {
+            let shard = ShardInfo {
+                canister,
+                created: ic_cdk::api::time(),
+                stable_bytes: 0,
+                uploads_allowed: !installing,
+                updated: 0,
+                error: installing.then(|| "shard is installing".into()),
+                installing,
+            };
+            self.shards.insert(canister, shard);
+        }
+    }
+    fn shard_installed(&mut self, canister: Principal, result: Result<(), String>) {
+        if let Some(mut shard) = self.shards.get(&canister) {
+            match result {
+                Ok(()) => {
+                    shard.installing = false;
+                    shard.uploads_allowed = true;
+                    shard.updated = ic_cdk::api::time();
+                    shard.error = None;
+                }
+                Err(err) => shard.error = Some(err),
+            }
+            self.shards.insert(canister, shard);
+        }
+    }
+    fn shards_installing(&self) -> Vec<Principal> {
+        self.shards
+            .iter()
+            .filter(|(_, shard)| shard.installing)
+            .map(|(canister, _)| canister)
+            .collect()
+    }
+    /// The installing shard is not refreshed, it is ready after it is installed
+    fn shard_refreshed(&mut self, canister: Principal, health: Result<HealthView, String>) {
+        if let Some(mut shard) = self.shards.get(&canister).filter(|shard| !shard.installing) {
+            match health {
+                Ok(health) => {
+                    shard.stable_bytes = health.stable_bytes;
+                    shard.uploads_allowed = health.uploads_allowed;
+                    shard.updated = ic_cdk::api::time();
+                    shard.error = None;
+                }
+                Err(err) => shard.error = Some(err),
+            }
+            self.shards.insert(canister, shard);
+        }
+    }
+    /// The least full shard that accepts uploads
+    fn shard_allocate(&self) -> Option<Principal> {
+        let max = self.settings.get().max_stable_bytes;
+        self.shards
+            .iter()
+            .map(|(_, shard)| shard)
+            .filter(|shard| shard.uploads_allowed && shard.stable_bytes < max)
+            .min_by_key(|shard| shard.stable_bytes)
+            .map(|shard| shard.canister)
+    }
+    fn anchors_record(&mut self, canister: Principal, anchors: Vec<String>) -> Result<(), String> {
+        if !self.shards.contains_key(&canister) {
+            return Err(format!("shard is missing: {}", canister.to_text()));
+        }
+        if let Some(anchor) = anchors.iter().find(|anchor| !anchor_of_canister(anchor, &canister)) {
+            return Err(format!("anchor is not issued for the shard: {anchor}"));
+        }
+        for anchor in anchors {
+            self.anchors.insert(anchor, canister);
+        }
+        Ok(())
+    }
+    /// The recorded shard, or the shard the anchor is issued for
+    fn anchor_lookup(&self, anchor: &str) -> Option<Principal> {
+        self.anchors.get(&anchor.to_string()).or_else(|| {
+            self.shards
+                .iter()
+                .map(|(canister, _)| canister)
+                .find(|canister| anchor_of_canister(anchor, canister))
+        })
+    }
+    fn wasm_module(&self) -> Vec<u8> {
+        self.wasm.iter().flat_map(|(_, chunk)| chunk).collect()
+    }
+}
+
+// ================== index ==================
+
+#[ic_cdk::update(guard = "must_be_admin")]
+fn index_settings_update(settings: IndexSettings) {
+    with_mut_index_state(|s| s.settings_update(settings))
+}
+#[ic_cdk::query]
+fn index_settings_query() -> IndexSettings {
+    with_index_state(|s| s.settings.get().to_owned())
+}
+/// Register the existing storage canister
+#[ic_cdk::update(guard = "must_be_admin")]
+fn index_shard_add(canister: Principal) {
+    with_mut_index_state(|s| s.shard_add(canister, false))
+}
+/// Install the spawned shard again, if installing or adding the administrators failed
+#[ic_cdk::update(guard = "must_be_admin")]
+async fn index_shard_install(canister: Principal) -> Result<(), String> {
+    if !with_index_state(|s| s.shards.get(&canister).is_some_and(|shard| shard.installing)) {
+        return Err(format!("shard is not installing: {}", canister.to_text()));
+    }
+    install_shard(canister).await
+}
+#[ic_cdk::query]
+fn index_shards() -> Vec<ShardInfo> {
+    with_index_state(|s| s.shards.iter().map(|(_, shard)| shard).collect())
+}
+/// Upload the wasm of the storage canister chunk by chunk, the first chunk clears the old one
+#[ic_cdk::update(guard = "must_be_admin")]
+fn index_wasm_upload(index: u32, chunk: Vec<u8>) {
+    with_mut_index_state(|s| {
+        if index == 0 {
+            s.wasm.clear_new();
+        }
+        s.wasm.insert(index, chunk);
+    })
+}
+
+/// The shard to upload new items, a new shard is spawned if all are full
+#[ic_cdk::update(guard = "must_be_admin")]
+async fn index_allocate() -> Result<Principal, String> {
+    if let Some(canister) = with_index_state(|s| s.shard_allocate()) {
+        return Ok(canister);
+    }
+    spawn_shard().await
+}
+
+/// Shards record the anchors of the new items, administrators can record for any shard
+#[ic_cdk::update]
+fn index_record(canister: Principal, anchors: Vec<String>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if caller != canister {
+        must_be_admin()?;
+    }
+    with_mut_index_state(|s| s.anchors_record(canister, anchors))
+}
+
+/// Which canister holds the anchors
+#[ic_cdk::query]
+fn index_lookup(anchors: Vec<String>) -> Vec<Option<Principal>> {
+    with_index_state(|s| {
+        anchors
+            .iter()
+            .take(MAX_LOOKUP_ANCHORS)
+            .map(|anchor| s.anchor_lookup(anchor))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "must_be_admin")]
+async fn index_refresh() {
+    refresh_shards().await
+}
+
+/// Called by the timers of the canister
+pub fn start_index_timers() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(REFRESH_INTERVAL_SECS), || {
+        ic_cdk::spawn(async {
+            for canister in with_index_state(|s| s.shards_installing()) {
+                let _ = install_shard(canister).await;
+            }
+            refresh_shards().await;
+            // Spawn in advance, so the allocation does not wait
+            if with_index_state(|s| {
+                s.shard_allocate().is_none() && !s.wasm.is_empty() && s.shards_installing().is_empty()
+            }) {
+                let _ = spawn_shard().await;
+            }
+        })
+    });
+}
+
+/// Refresh the usage of the shards by their health
+async fn refresh_shards() {
+    let shards: Vec<Principal> = with_index_state(|s| s.shards.iter().map(|(canister, _)| canister).collect());
+    for canister in shards {
+        let health = ic_cdk::call::<_, (HealthView,)>(canister, "health", ())
+            .await
+            .map(|(health,)| health)
+            .map_err(|(code, message)| format!("refresh failed: {code:?} {message}"));
+        with_mut_index_state(|s| s.shard_refreshed(canister, health));
+    }
+}
+
+struct SpawningGuard;
+
+impl Drop for SpawningGuard {
+    fn drop(&mut self) {
+        SPAWNING.with(|spawning| spawning.set(false));
+    }
+}
+
+/// Create the canister and install the uploaded wasm, the index is the administrator of the new shard.
+/// Only one shard is spawned at a time, the shard is recorded once it is created,
+/// so the cycles are not lost if the install fails, and the install is retried by the timer
+async fn spawn_shard() -> Result<Principal, String> {
+    if SPAWNING.with(|spawning| spawning.replace(true)) {
+        return Err("shard is spawning, try again later".into());
+    }
+    let _guard = SpawningGuard;
+
+    let settings = with_index_state(|s| s.settings.get().to_owned());
+    if with_index_state(|s| s.wasm.is_empty()) {
+        return Err("wasm of the storage canister is missing".into());
+    }
+
+    let mut controllers = settings.controllers.clone();
+    controllers.push(ic_cdk::id());
+    let argument = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(controllers),
+            ..Default::default()
+        }),
+    };
+    let (record,) = create_canister(argument, settings.cycles)
+        .await
+        .map_err(|(code, message)| format!("create canister failed: {code:?} {message}"))?;
+    let canister = record.canister_id;
+    with_mut_index_state(|s| s.shard_add(canister, true));
+
+    inner_install_shard(canister).await?;
+    Ok(canister)
+}
+
+/// Install the shard again, only one shard is installed at a time
+async fn install_shard(canister: Principal) -> Result<(), String> {
+    if SPAWNING.with(|spawning| spawning.replace(true)) {
+        return Err("shard is spawning, try again later".into());
+    }
+    let _guard = SpawningGuard;
+
+    inner_install_shard(canister).await
+}
+
+/// The shard holds nothing before it is installed, so the wasm is reinstalled if the last install failed
+async fn inner_install_shard(canister: Principal) -> Result<(), String> {
+    let result: Result<(), String> = async {
+        let (settings, wasm_module) = with_index_state(|s| (s.settings.get().to_owned(), s.wasm_module()));
+        if wasm_module.is_empty() {
+            return Err("wasm of the storage canister is missing".into());
+        }
+        install_code(InstallCodeArgument {
+            mode: CanisterInstallMode::Reinstall,
+            canister_id: canister,
+            wasm_module,
+            arg: candid::encode_args(()).map_err(|err| format!("encode args failed: {err}"))?,
+        })
+        .await
+        .map_err(|(code, message)| format!("install code failed: {code:?} {message}"))?;
+
+        for admin in settings.admins {
+            ic_cdk::call::<_, ()>(canister, "admin_add", (admin,))
+                .await
+                .map_err(|(code, message)| format!("add admin failed: {code:?} {message}"))?;
+        }
+        Ok(())
+    }
+    .await;
+    with_mut_index_state(|s| s.shard_installed(canister, result.clone()));
+    result
+}
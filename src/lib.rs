@@ -12,7 +12,17 @@ mod types;
 
 mod stable;
 
+mod token;
+
+mod icrc;
+
 mod apis;
 
+#[cfg(feature = "index")]
+mod index;
+
+#[cfg(feature = "cli")]
+pub mod offline;
+
 #[cfg(test)]
 mod test;
@@ -0,0 +1,430 @@
+//! Inspect the exported snapshots and the raw stable memory dumps without a replica
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use jelly_model::store::{
+    api::ApiData,
+    code::CodeData,
+    combined::Combined,
+    dapp::{anchor::DappParsedId, Dapp},
+    publisher::Publisher,
+};
+
+use crate::stable::{
+    load_stable_memory, with_state, MEMORY_ID_APIS, MEMORY_ID_CODE, MEMORY_ID_COMBINED, MEMORY_ID_COMBINED_CALLED,
+    MEMORY_ID_DAPP, MEMORY_ID_DAPP_ACCESSED, MEMORY_ID_DAPP_ACCESSES, MEMORY_ID_DAPP_ACCESS_RULES,
+    MEMORY_ID_DAPP_CALLED, MEMORY_ID_DAPP_COLLECTED, MEMORY_ID_DAPP_FROZEN, MEMORY_ID_OWNERS, MEMORY_ID_PUBLISHER,
+    MEMORY_ID_PUBLISHER_USAGE,
+};
+use crate::types::*;
+
+const MAX_DIFF_ITEMS: usize = 20; // Max listed items of every map in the diff
+
+/// Records of the snapshot, grouped by the maps
+#[derive(Default)]
+pub struct Snapshot {
+    maps: Vec<SnapshotMapInfo>, // From the manifest
+    records: BTreeMap<u8, BTreeMap<Vec<u8>, Vec<u8>>>,
+    checksum: Vec<u8>,     // Checksum of the last page
+    problems: Vec<String>, // Found while reading the pages
+}
+
+impl Snapshot {
+    /// Read the exported pages, every ExportPage is appended to the file as CBOR
+    pub fn from_export(bytes: &[u8]) -> Result<Self, String> {
+        let mut snapshot = Self::default();
+        let mut reader = bytes;
+        let mut index = 0;
+        let mut last = false;
+        while !reader.is_empty() {
+            let page: ExportPage =
+                ciborium::de::from_reader(&mut reader).map_err(|err| format!("wrong page {index}: {err}"))?;
+            if snapshot_checksum(&snapshot.checksum, &page.data) != page.checksum {
+                snapshot
+                    .problems
+                    .push(format!("checksum of page {index} is mismatched"));
+            }
+            let data: SnapshotPage =
+                ciborium::de::from_reader(&page.data[..]).map_err(|err| format!("wrong page {index}: {err}"))?;
+            if data.version != SNAPSHOT_VERSION {
+                return Err(format!("unsupported snapshot version: {}", data.version));
+            }
+            if data.page != index {
+                snapshot
+                    .problems
+                    .push(format!("page {index} is numbered {}", data.page));
+            }
+            if last {
+                snapshot.problems.push(format!("page {index} is after the last page"));
+            }
+            match data.manifest {
+                Some(manifest) => snapshot.maps = manifest.maps,
+                None if index == 0 => snapshot.problems.push("manifest is missing".into()),
+                None => {}
+            }
+            for record in data.records {
+                snapshot
+                    .records
+                    .entry(record.map)
+                    .or_default()
+                    .insert(record.key, record.value);
+            }
+            snapshot.checksum = page.checksum;
+            last = data.last;
+            index += 1;
+        }
+        if !last {
+            snapshot.problems.push("the last page is missing".into());
+        }
+        Ok(snapshot)
+    }
+
+    /// Read the raw stable memory of the canister
+    pub fn from_dump(bytes: Vec<u8>) -> Self {
+        load_stable_memory(bytes);
+        let (maps, records) = with_state(|s| s.snapshot_all());
+        let mut snapshot = Self {
+            maps,
+            ..Default::default()
+        };
+        for record in records {
+            snapshot
+                .records
+                .entry(record.map)
+                .or_default()
+                .insert(record.key, record.value);
+        }
+        snapshot
+    }
+
+    /// Checksum of the last page, empty for the dumps
+    pub fn checksum(&self) -> String {
+        hex(&self.checksum)
+    }
+
+    /// Entries of every map, or the items of the map
+    pub fn list(&self, map: Option<&str>) -> Result<Vec<String>, String> {
+        let Some(map) = map else {
+            return Ok(self
+                .maps
+                .iter()
+                .map(|m| format!("{:>3} {:<24} {}", m.id, m.name, self.entries(m.id)))
+                .collect());
+        };
+        let id = self.map_id(map)?;
+        Ok(self
+            .records
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| describe(id, key, value))
+            .collect())
+    }
+
+    /// The dapp with its counters and access rules
+    pub fn dapp(&self, anchor: &str) -> Result<String, String> {
+        let id: DappParsedId = anchor.try_into()?;
+        let key = WrappedDappId::from(id).to_bytes().to_vec();
+        let dapp = self
+            .get(MEMORY_ID_DAPP, &key)
+            .map(|value| Dapp::from_bytes(Cow::Borrowed(value)))
+            .ok_or("dapp is missing")?;
+
+        let mut lines = vec![serde_json::to_string_pretty(&dapp).map_err(|err| format!("serialize failed: {err}"))?];
+        for (map, name) in [
+            (MEMORY_ID_DAPP_ACCESSED, "accessed"),
+            (MEMORY_ID_DAPP_CALLED, "called"),
+            (MEMORY_ID_DAPP_COLLECTED, "collected"),
+        ] {
+            let count = self.get(map, &key).map(|value| u64::from_bytes(Cow::Borrowed(value)));
+            lines.push(format!(
+                "{name}: {}",
+                count.map_or("missing".to_string(), |c| c.to_string())
+            ));
+        }
+        let rules = self
+            .get(MEMORY_ID_DAPP_ACCESS_RULES, &key)
+            .map(|value| DappAccessRules::from_bytes(Cow::Borrowed(value)));
+        lines.push(format!(
+            "access rules: {}",
+            serde_json::to_string(&rules).map_err(|err| format!("serialize failed: {err}"))?
+        ));
+        if let Some(frozen) = self
+            .get(MEMORY_ID_DAPP_FROZEN, &key)
+            .map(|value| DappFrozen::from_bytes(Cow::Borrowed(value)))
+        {
+            lines.push(format!("frozen by {}: {}", frozen.moderator, frozen.reason));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Added, removed and changed items of every map
+    pub fn diff(&self, other: &Snapshot) -> Vec<String> {
+        let empty = BTreeMap::new();
+        let ids: BTreeSet<u8> = self.records.keys().chain(other.records.keys()).copied().collect();
+        let mut lines = vec![];
+        for id in ids {
+            let before = self.records.get(&id).unwrap_or(&empty);
+            let after = other.records.get(&id).unwrap_or(&empty);
+            let added: Vec<_> = after.iter().filter(|(key, _)| !before.contains_key(*key)).collect();
+            let removed: Vec<_> = before.iter().filter(|(key, _)| !after.contains_key(*key)).collect();
+            let changed: Vec<_> = after
+                .iter()
+                .filter(|(key, value)| before.get(*key).is_some_and(|v| v != *value))
+                .collect();
+            if added.is_empty() && removed.is_empty() && changed.is_empty() {
+                continue;
+            }
+            lines.push(format!(
+                "{}: +{} -{} ~{}",
+                self.map_name(id),
+                added.len(),
+                removed.len(),
+                changed.len()
+            ));
+            for (sign, items) in [("+", &added), ("-", &removed), ("~", &changed)] {
+                for (key, value) in items.iter().take(MAX_DIFF_ITEMS) {
+                    lines.push(format!("  {sign} {}", describe(id, key, value)));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Integrity checks, nothing is returned if the snapshot is good
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = self.problems.clone();
+
+        for map in &self.maps {
+            let entries = self.entries(map.id);
+            if entries != map.entries {
+                problems.push(format!(
+                    "entries of {} are mismatched: {entries} != {}",
+                    map.name, map.entries
+                ));
+            }
+        }
+
+        for key in self.keys(MEMORY_ID_DAPP) {
+            for map in [
+                MEMORY_ID_DAPP_ACCESSES,
+                MEMORY_ID_DAPP_ACCESSED,
+                MEMORY_ID_DAPP_CALLED,
+                MEMORY_ID_DAPP_COLLECTED,
+            ] {
+                if self.get(map, key).is_none() {
+                    problems.push(format!("{} of dapp {} is missing", self.map_name(map), hex(key)));
+                }
+            }
+        }
+        for key in self.keys(MEMORY_ID_COMBINED) {
+            if self.get(MEMORY_ID_COMBINED_CALLED, key).is_none() {
+                problems.push(format!("called of combined {} is missing", hex(key)));
+            }
+        }
+
+        // Items of the publishers
+        for (key, publisher) in self.records.get(&MEMORY_ID_OWNERS).into_iter().flatten() {
+            if self.get(MEMORY_ID_PUBLISHER, publisher).is_none() {
+                problems.push(format!("owner of {} is missing: {}", hex(key), hex(publisher)));
+            }
+        }
+        for key in self.keys(MEMORY_ID_PUBLISHER_USAGE) {
+            if self.get(MEMORY_ID_PUBLISHER, key).is_none() {
+                problems.push(format!("publisher of the usage is missing: {}", hex(key)));
+            }
+        }
+
+        problems
+    }
+
+    fn get(&self, map: u8, key: &[u8]) -> Option<&Vec<u8>> {
+        self.records.get(&map).and_then(|records| records.get(key))
+    }
+    fn keys(&self, map: u8) -> impl Iterator<Item = &Vec<u8>> {
+        self.records.get(&map).into_iter().flat_map(|records| records.keys())
+    }
+    fn entries(&self, map: u8) -> u64 {
+        self.records
+            .get(&map)
+            .map(|records| records.len() as u64)
+            .unwrap_or_default()
+    }
+    fn map_name(&self, id: u8) -> String {
+        self.maps
+            .iter()
+            .find(|m| m.id == id)
+            .map(|m| m.name.clone())
+            .unwrap_or_else(|| format!("map {id}"))
+    }
+    fn map_id(&self, name: &str) -> Result<u8, String> {
+        self.maps
+            .iter()
+            .find(|m| m.name == name || m.id.to_string() == name)
+            .map(|m| m.id)
+            .ok_or_else(|| format!("unknown map: {name}"))
+    }
+}
+
+/// Anchor of the entity, or the key in hex
+fn describe(map: u8, key: &[u8], value: &[u8]) -> String {
+    let value = Cow::Borrowed(value);
+    match map {
+        MEMORY_ID_PUBLISHER => Publisher::from_bytes(value).anchor.as_ref().to_string(),
+        MEMORY_ID_CODE => CodeData::from_bytes(value).anchor.as_ref().to_string(),
+        MEMORY_ID_APIS => ApiData::from_bytes(value).anchor.as_ref().to_string(),
+        MEMORY_ID_COMBINED => Combined::from_bytes(value).anchor.as_ref().to_string(),
+        MEMORY_ID_DAPP => Dapp::from_bytes(value).id.as_ref().to_string(),
+        _ => hex(key),
+    }
+}
+
+/// Decode the raw reply of state_export printed by `dfx canister call --output raw`,
+/// return the CBOR of the page to be appended to the snapshot file and the cursor of the next page
+pub fn export_page(reply: &str) -> Result<(Vec<u8>, Option<ExportCursor>), String> {
+    let reply = unhex(reply.trim())?;
+    let page: Result<ExportPage, String> =
+        candid::decode_one(&reply).map_err(|err| format!("wrong reply of state_export: {err}"))?;
+    let page = page?;
+    let mut data = vec![];
+    ciborium::ser::into_writer(&page, &mut data).map_err(|err| format!("encode page failed: {err}"))?;
+    Ok((data, page.next))
+}
+
+/// The candid argument of state_export for the next page
+pub fn cursor_argument(cursor: &ExportCursor) -> String {
+    let after = match &cursor.after {
+        Some(after) => format!("opt {}", blob(after)),
+        None => "null".into(),
+    };
+    format!(
+        "(opt record {{ page = {} : nat64; map = {} : nat8; after = {after}; checksum = {} }})",
+        cursor.page,
+        cursor.map,
+        blob(&cursor.checksum)
+    )
+}
+
+fn blob(bytes: &[u8]) -> String {
+    let escaped: String = bytes.iter().map(|b| format!("\\{b:02x}")).collect();
+    format!("blob \"{escaped}\"")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    if text.len() % 2 != 0 {
+        return Err("wrong hex: odd length".into());
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("wrong hex: {}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: u8, value: u64) -> SnapshotRecord {
+        SnapshotRecord {
+            map: MEMORY_ID_COMBINED_CALLED,
+            key: vec![key],
+            value: value.to_bytes().to_vec(),
+        }
+    }
+
+    /// The raw reply of state_export
+    fn reply(page: SnapshotPage, last: &[u8]) -> (String, ExportPage) {
+        let mut data = vec![];
+        ciborium::ser::into_writer(&page, &mut data).unwrap();
+        let checksum = snapshot_checksum(last, &data);
+        let next = (!page.last).then(|| ExportCursor {
+            page: page.page + 1,
+            map: MEMORY_ID_COMBINED_CALLED,
+            after: page.records.last().map(|r| r.key.clone()),
+            checksum: checksum.clone(),
+        });
+        let page = ExportPage { data, checksum, next };
+        let reply = candid::encode_one(Ok::<_, String>(page.clone())).unwrap();
+        (hex(&reply), page)
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let manifest = SnapshotManifest {
+            canister: candid::Principal::anonymous(),
+            created: 1,
+            maps: vec![SnapshotMapInfo {
+                id: MEMORY_ID_COMBINED_CALLED,
+                name: "combined_called".into(),
+                entries: 3,
+            }],
+        };
+        let (first, first_page) = reply(
+            SnapshotPage {
+                version: SNAPSHOT_VERSION,
+                page: 0,
+                manifest: Some(manifest),
+                records: vec![record(1, 10), record(2, 20)],
+                last: false,
+            },
+            &[],
+        );
+        let (second, second_page) = reply(
+            SnapshotPage {
+                version: SNAPSHOT_VERSION,
+                page: 1,
+                manifest: None,
+                records: vec![record(3, 30)],
+                last: true,
+            },
+            &first_page.checksum,
+        );
+
+        // Appended like the append command
+        let mut file = vec![];
+        let (page, next) = export_page(&first).unwrap();
+        file.extend(page);
+        assert_eq!(next, first_page.next);
+        let (page, next) = export_page(&second).unwrap();
+        file.extend(page);
+        assert_eq!(next, None);
+
+        let snapshot = Snapshot::from_export(&file).unwrap();
+        assert!(snapshot.check().is_empty());
+        assert_eq!(snapshot.checksum(), hex(&second_page.checksum));
+        assert_eq!(snapshot.list(None).unwrap().len(), 1);
+        assert_eq!(snapshot.list(Some("combined_called")).unwrap(), vec!["01", "02", "03"]);
+        assert!(snapshot.diff(&snapshot).is_empty());
+
+        // The missing page is found
+        let (page, _) = export_page(&first).unwrap();
+        let snapshot = Snapshot::from_export(&page).unwrap();
+        assert_eq!(snapshot.check().len(), 2); // The last page and the entries
+    }
+
+    #[test]
+    fn test_cursor_argument() {
+        let cursor = ExportCursor {
+            page: 2,
+            map: 50,
+            after: Some(vec![1, 255]),
+            checksum: vec![171],
+        };
+        assert_eq!(
+            cursor_argument(&cursor),
+            r#"(opt record { page = 2 : nat64; map = 50 : nat8; after = opt blob "\01\ff"; checksum = blob "\ab" })"#
+        );
+        assert_eq!(unhex("01ff").unwrap(), vec![1, 255]);
+        assert!(unhex("0").is_err());
+        assert!(unhex("zz").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
 
 use jelly_model::{
     store::{
@@ -28,6 +29,7 @@ use jelly_model::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::token::{check_public_key, check_token_expires, verify_signed_token};
 use crate::types::*;
 
 #[derive(Serialize, Deserialize)]
@@ -35,10 +37,30 @@ pub struct State {
     /// administrator
     #[serde(skip, default = "init_admin_data")]
     admin: StableCell<AdminUsers>,
+    #[serde(skip, default = "init_settings_data")]
+    settings: StableCell<Settings>,
+    #[serde(skip, default = "init_import_data")]
+    import: StableCell<ImportProgress>, // Not in the snapshot
+    #[serde(skip, default = "init_export_data")]
+    export: StableCell<ExportProgress>, // Not in the snapshot
 
     /// Publisher
     #[serde(skip, default = "init_publisher_data")]
     publisher: StableBTreeMap<PublisherId, Publisher>,
+    #[serde(skip, default = "init_publisher_keys_data")]
+    publisher_keys: StableBTreeMap<PublisherId, PublisherKey>, // Keys to sign access tokens
+    #[serde(skip, default = "init_publisher_controllers_data")]
+    publisher_controllers: StableBTreeMap<PublisherId, Principal>, // The principal can write as the publisher
+    #[serde(skip, default = "init_owners_data")]
+    owners: StableBTreeMap<OwnedKey, PublisherId>, // Items uploaded by publishers
+    #[serde(skip, default = "init_publisher_quotas_data")]
+    publisher_quotas: StableBTreeMap<PublisherId, QuotaLimit>, // Use the default quota if missing
+    #[serde(skip, default = "init_publisher_usage_data")]
+    publisher_usage: StableBTreeMap<PublisherId, PublisherUsage>,
+    #[serde(skip, default = "init_upload_receipts_data")]
+    upload_receipts: StableBTreeMap<u64, UploadReceipt>, // Receipts of the uploads by publishers
+    #[serde(skip, default = "init_pending_receipts_data")]
+    pending_receipts: StableBTreeMap<u64, UploadReceipt>, // Receipts recorded while the snapshot is frozen
 
     #[serde(skip, default = "init_code_data")]
     code: StableBTreeMap<CodeDataHash, CodeData>,
@@ -61,14 +83,55 @@ pub struct State {
     dapp_called: StableBTreeMap<WrappedDappId, u64>,
     #[serde(skip, default = "init_dapp_collected_data")]
     dapp_collected: StableBTreeMap<WrappedDappId, u64>,
+    #[serde(skip, default = "init_dapp_unique_data")]
+    dapp_unique: StableBTreeMap<DappDayKey, UniqueUsers>, // Unique callers of every day
+    #[serde(skip, default = "init_dapp_collections_data")]
+    dapp_collections: StableBTreeMap<UserCollectionKey, u64>, // Collected time of the user
+    #[serde(skip, default = "init_dapp_frozen_data")]
+    dapp_frozen: StableBTreeMap<WrappedDappId, DappFrozen>, // Moderation records
+    #[serde(skip, default = "init_dapp_access_rules_data")]
+    dapp_access_rules: StableBTreeMap<WrappedDappId, DappAccessRules>, // Rules checked by the canister
+    #[serde(skip, default = "init_token_nonces_data")]
+    token_nonces: StableBTreeMap<TokenNonceKey, PublisherId>, // Used nonces of the signed tokens and the issuer
+
+    #[serde(skip, default = "init_ranks_data")]
+    ranks: StableBTreeMap<RankKey, ()>, // Leaderboards
+    #[serde(skip, default = "init_period_counters_data")]
+    period_counters: StableBTreeMap<PeriodCounterKey, u64>, // Counters of every day and week
+
+    /// Replication, not in the snapshot
+    #[serde(skip, default = "init_replication_log_data")]
+    replication_log: StableBTreeMap<u64, ReplicaOp>, // Ops not pushed to all secondaries
+    #[serde(skip, default = "init_replicas_data")]
+    replicas: StableBTreeMap<Principal, ReplicaStatus>, // Secondaries on the primary, the primary on the secondary
+    #[serde(skip, default = "init_replication_seq_data")]
+    replication_seq: StableCell<u64>, // Sequence of the next op
+
+    /// Change feed, not in the snapshot
+    #[serde(skip, default = "init_changes_data")]
+    changes: StableBTreeMap<u64, ChangeEvent>,
+    #[serde(skip, default = "init_changes_seq_data")]
+    changes_seq: StableCell<u64>, // Sequence of the next event
+    #[serde(skip, default = "init_changes_pending_data")]
+    changes_pending: StableBTreeMap<OwnedKey, ()>, // Counters changed since the last flush
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
             admin: init_admin_data(),
+            settings: init_settings_data(),
+            import: init_import_data(),
+            export: init_export_data(),
 
             publisher: init_publisher_data(),
+            publisher_keys: init_publisher_keys_data(),
+            publisher_controllers: init_publisher_controllers_data(),
+            owners: init_owners_data(),
+            publisher_quotas: init_publisher_quotas_data(),
+            publisher_usage: init_publisher_usage_data(),
+            upload_receipts: init_upload_receipts_data(),
+            pending_receipts: init_pending_receipts_data(),
 
             code: init_code_data(),
 
@@ -82,6 +145,22 @@ impl Default for State {
             dapp_accessed: init_dapp_accessed_data(),
             dapp_called: init_dapp_called_data(),
             dapp_collected: init_dapp_collected_data(),
+            dapp_unique: init_dapp_unique_data(),
+            dapp_collections: init_dapp_collections_data(),
+            dapp_frozen: init_dapp_frozen_data(),
+            dapp_access_rules: init_dapp_access_rules_data(),
+            token_nonces: init_token_nonces_data(),
+
+            ranks: init_ranks_data(),
+            period_counters: init_period_counters_data(),
+
+            replication_log: init_replication_log_data(),
+            replicas: init_replicas_data(),
+            replication_seq: init_replication_seq_data(),
+
+            changes: init_changes_data(),
+            changes_seq: init_changes_seq_data(),
+            changes_pending: init_changes_pending_data(),
         }
     }
 }
@@ -92,25 +171,164 @@ thread_local! {
     static STATE: RefCell<State> = RefCell::default();
 }
 
-const MEMORY_ID_ADMIN: MemoryId = MemoryId::new(0); // Administrator data
-
-const MEMORY_ID_PUBLISHER: MemoryId = MemoryId::new(10); // Publisher metadata
+pub(crate) const MEMORY_ID_ADMIN: u8 = 0; // Administrator data
+pub(crate) const MEMORY_ID_SETTINGS: u8 = 1; // Settings of the canister
+pub(crate) const MEMORY_ID_IMPORT: u8 = 2; // Progress of the import
+pub(crate) const MEMORY_ID_EXPORT: u8 = 3; // Progress of the export
+
+pub(crate) const MEMORY_ID_PUBLISHER: u8 = 10; // Publisher metadata
+pub(crate) const MEMORY_ID_PUBLISHER_KEYS: u8 = 11; // Publisher keys
+pub(crate) const MEMORY_ID_PUBLISHER_CONTROLLERS: u8 = 12; // Publisher controllers
+pub(crate) const MEMORY_ID_OWNERS: u8 = 13; // Owners of the uploaded items
+pub(crate) const MEMORY_ID_PUBLISHER_QUOTAS: u8 = 14; // Publisher quotas
+pub(crate) const MEMORY_ID_PUBLISHER_USAGE: u8 = 15; // Publisher usage
+pub(crate) const MEMORY_ID_UPLOAD_RECEIPTS: u8 = 16; // Upload receipts
+pub(crate) const MEMORY_ID_PENDING_RECEIPTS: u8 = 17; // Upload receipts while exporting or importing
+
+pub(crate) const MEMORY_ID_CODE: u8 = 20; // Code data
+
+pub(crate) const MEMORY_ID_APIS: u8 = 30; // Api data
+
+pub(crate) const MEMORY_ID_COMBINED: u8 = 40; // Content data
+pub(crate) const MEMORY_ID_COMBINED_CALLED: u8 = 41; // combined data
+
+pub(crate) const MEMORY_ID_DAPP: u8 = 50; // dapp data
+pub(crate) const MEMORY_ID_DAPP_ACCESSES: u8 = 51; // dapp data
+pub(crate) const MEMORY_ID_DAPP_ACCESSED: u8 = 52; // dapp data
+pub(crate) const MEMORY_ID_DAPP_CALLED: u8 = 53; // dapp data
+pub(crate) const MEMORY_ID_DAPP_COLLECTED: u8 = 54; // dapp data
+pub(crate) const MEMORY_ID_DAPP_UNIQUE: u8 = 55; // dapp data
+pub(crate) const MEMORY_ID_DAPP_COLLECTIONS: u8 = 56; // dapp data
+pub(crate) const MEMORY_ID_DAPP_FROZEN: u8 = 57; // dapp data
+pub(crate) const MEMORY_ID_DAPP_ACCESS_RULES: u8 = 58; // dapp data
+pub(crate) const MEMORY_ID_TOKEN_NONCES: u8 = 59; // dapp data
+
+pub(crate) const MEMORY_ID_RANKS: u8 = 60; // Leaderboards
+pub(crate) const MEMORY_ID_PERIOD_COUNTERS: u8 = 61; // Counters of periods
+
+pub(crate) const MEMORY_ID_REPLICATION_LOG: u8 = 70; // Replication
+pub(crate) const MEMORY_ID_REPLICAS: u8 = 71; // Replication
+pub(crate) const MEMORY_ID_REPLICATION_SEQ: u8 = 72; // Replication
+
+pub(crate) const MEMORY_ID_CHANGES: u8 = 73; // Change feed
+pub(crate) const MEMORY_ID_CHANGES_SEQ: u8 = 74; // Change feed
+pub(crate) const MEMORY_ID_CHANGES_PENDING: u8 = 75; // Change feed
+
+pub fn get_virtual_memory(memory_id: u8) -> VirtualMemory {
+    MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(MemoryId::new(memory_id)))
+}
 
-const MEMORY_ID_CODE: MemoryId = MemoryId::new(20); // Code data
+fn memory_stats(id: u8, name: &str, entries: u64) -> MemoryStats {
+    use ic_stable_structures::Memory;
+    let pages = get_virtual_memory(id).size();
+    MemoryStats {
+        id,
+        name: name.to_string(),
+        entries,
+        pages,
+        bytes: pages * WASM_PAGE_SIZE,
+    }
+}
 
-const MEMORY_ID_APIS: MemoryId = MemoryId::new(30); // Api data
+// =============== snapshot ===============
 
-const MEMORY_ID_COMBINED: MemoryId = MemoryId::new(40); // Content data
-const MEMORY_ID_COMBINED_CALLED: MemoryId = MemoryId::new(41); // combined data
+/// Maps and cells in the snapshot, the records are the Storable encodings
+trait SnapshotMap {
+    fn entries(&self) -> u64;
+    /// Export the records after the key until the budget is used up, return true if all records are exported
+    fn export(
+        &self,
+        id: u8,
+        after: &mut Option<Vec<u8>>,
+        budget: &mut usize,
+        records: &mut Vec<SnapshotRecord>,
+    ) -> bool;
+    /// Insert the record as it is, nothing is checked
+    fn import(&mut self, key: &[u8], value: &[u8]) -> Result<(), String>;
+    fn remove(&mut self, key: &[u8]);
+    fn wipe(&mut self);
+}
 
-const MEMORY_ID_DAPP: MemoryId = MemoryId::new(50); // dapp data
-const MEMORY_ID_DAPP_ACCESSES: MemoryId = MemoryId::new(51); // dapp data
-const MEMORY_ID_DAPP_ACCESSED: MemoryId = MemoryId::new(52); // dapp data
-const MEMORY_ID_DAPP_CALLED: MemoryId = MemoryId::new(53); // dapp data
-const MEMORY_ID_DAPP_COLLECTED: MemoryId = MemoryId::new(54); // dapp data
+impl<K: Storable + Ord + Clone, V: Storable> SnapshotMap for StableBTreeMap<K, V> {
+    fn entries(&self) -> u64 {
+        self.len()
+    }
+    fn export(
+        &self,
+        id: u8,
+        after: &mut Option<Vec<u8>>,
+        budget: &mut usize,
+        records: &mut Vec<SnapshotRecord>,
+    ) -> bool {
+        use std::ops::Bound::{Excluded, Unbounded};
+        let start = match after {
+            Some(key) => Excluded(K::from_bytes(Cow::Borrowed(key))),
+            None => Unbounded,
+        };
+        for (key, value) in self.range((start, Unbounded)) {
+            let key = key.to_bytes().to_vec();
+            let value = value.to_bytes().to_vec();
+            let size = key.len() + value.len();
+            if *budget < size && !records.is_empty() {
+                return false; // At least one record in a page
+            }
+            *budget = budget.saturating_sub(size);
+            *after = Some(key.clone());
+            records.push(SnapshotRecord { map: id, key, value });
+        }
+        true
+    }
+    fn import(&mut self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.insert(K::from_bytes(Cow::Borrowed(key)), V::from_bytes(Cow::Borrowed(value)));
+        Ok(())
+    }
+    fn remove(&mut self, key: &[u8]) {
+        self.remove(&K::from_bytes(Cow::Borrowed(key)));
+    }
+    fn wipe(&mut self) {
+        self.clear_new();
+    }
+}
 
-fn get_virtual_memory(memory_id: MemoryId) -> VirtualMemory {
-    MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(memory_id))
+impl<T: Storable + Default> SnapshotMap for StableCell<T> {
+    fn entries(&self) -> u64 {
+        1
+    }
+    fn export(
+        &self,
+        id: u8,
+        after: &mut Option<Vec<u8>>,
+        budget: &mut usize,
+        records: &mut Vec<SnapshotRecord>,
+    ) -> bool {
+        if after.is_some() {
+            return true;
+        }
+        let value = self.get().to_bytes().to_vec();
+        if *budget < value.len() && !records.is_empty() {
+            return false;
+        }
+        *budget = budget.saturating_sub(value.len());
+        *after = Some(vec![]);
+        records.push(SnapshotRecord {
+            map: id,
+            key: vec![],
+            value,
+        });
+        true
+    }
+    fn import(&mut self, _key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.set(T::from_bytes(Cow::Borrowed(value)))
+            .map(|_| ())
+            .map_err(|err| format!("set cell failed: {err:?}"))
+    }
+    fn remove(&mut self, _key: &[u8]) {
+        self.wipe();
+    }
+    fn wipe(&mut self) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.set(T::default()).unwrap();
+    }
 }
 
 // =============== admin ===============
@@ -136,11 +354,127 @@ impl Storable for AdminUsers {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+fn init_settings_data() -> StableCell<Settings> {
+    #[allow(clippy::expect_used)] // ? SAFETY
+    StableCell::init(get_virtual_memory(MEMORY_ID_SETTINGS), Default::default()).expect("failed to initialize")
+}
+
+fn init_import_data() -> StableCell<ImportProgress> {
+    #[allow(clippy::expect_used)] // ? SAFETY
+    StableCell::init(get_virtual_memory(MEMORY_ID_IMPORT), Default::default()).expect("failed to initialize")
+}
+
+impl Storable for ImportProgress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+fn init_export_data() -> StableCell<ExportProgress> {
+    #[allow(clippy::expect_used)] // ? SAFETY
+    StableCell::init(get_virtual_memory(MEMORY_ID_EXPORT), Default::default()).expect("failed to initialize")
+}
+
+impl Storable for ExportProgress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Settings {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 // =============== publisher ===============
 
 fn init_publisher_data() -> StableBTreeMap<PublisherId, Publisher> {
     StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PUBLISHER))
 }
+fn init_publisher_keys_data() -> StableBTreeMap<PublisherId, PublisherKey> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PUBLISHER_KEYS))
+}
+fn init_publisher_controllers_data() -> StableBTreeMap<PublisherId, Principal> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PUBLISHER_CONTROLLERS))
+}
+fn init_owners_data() -> StableBTreeMap<OwnedKey, PublisherId> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_OWNERS))
+}
+fn init_publisher_quotas_data() -> StableBTreeMap<PublisherId, QuotaLimit> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PUBLISHER_QUOTAS))
+}
+fn init_publisher_usage_data() -> StableBTreeMap<PublisherId, PublisherUsage> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PUBLISHER_USAGE))
+}
+fn init_upload_receipts_data() -> StableBTreeMap<u64, UploadReceipt> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_UPLOAD_RECEIPTS))
+}
+fn init_pending_receipts_data() -> StableBTreeMap<u64, UploadReceipt> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PENDING_RECEIPTS))
+}
+
+impl Storable for UploadReceipt {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PublisherKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
 // =============== code ===============
 
@@ -179,6 +513,145 @@ fn init_dapp_called_data() -> StableBTreeMap<WrappedDappId, u64> {
 fn init_dapp_collected_data() -> StableBTreeMap<WrappedDappId, u64> {
     StableBTreeMap::init(get_virtual_memory(MEMORY_ID_DAPP_COLLECTED))
 }
+fn init_dapp_unique_data() -> StableBTreeMap<DappDayKey, UniqueUsers> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_DAPP_UNIQUE))
+}
+fn init_dapp_collections_data() -> StableBTreeMap<UserCollectionKey, u64> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_DAPP_COLLECTIONS))
+}
+fn init_dapp_frozen_data() -> StableBTreeMap<WrappedDappId, DappFrozen> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_DAPP_FROZEN))
+}
+fn init_dapp_access_rules_data() -> StableBTreeMap<WrappedDappId, DappAccessRules> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_DAPP_ACCESS_RULES))
+}
+fn init_token_nonces_data() -> StableBTreeMap<TokenNonceKey, PublisherId> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_TOKEN_NONCES))
+}
+
+impl Storable for DappFrozen {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for DappAccessRules {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// =============== ranks ===============
+
+fn init_ranks_data() -> StableBTreeMap<RankKey, ()> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_RANKS))
+}
+fn init_period_counters_data() -> StableBTreeMap<PeriodCounterKey, u64> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_PERIOD_COUNTERS))
+}
+
+// =============== replication ===============
+
+fn init_replication_log_data() -> StableBTreeMap<u64, ReplicaOp> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_REPLICATION_LOG))
+}
+fn init_replicas_data() -> StableBTreeMap<Principal, ReplicaStatus> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_REPLICAS))
+}
+fn init_replication_seq_data() -> StableCell<u64> {
+    #[allow(clippy::expect_used)] // ? SAFETY
+    StableCell::init(get_virtual_memory(MEMORY_ID_REPLICATION_SEQ), 0).expect("failed to initialize")
+}
+
+impl Storable for ReplicaOp {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ReplicaStatus {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// =============== changes ===============
+
+fn init_changes_data() -> StableBTreeMap<u64, ChangeEvent> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_CHANGES))
+}
+fn init_changes_seq_data() -> StableCell<u64> {
+    #[allow(clippy::expect_used)] // ? SAFETY
+    StableCell::init(get_virtual_memory(MEMORY_ID_CHANGES_SEQ), 0).expect("failed to initialize")
+}
+fn init_changes_pending_data() -> StableBTreeMap<OwnedKey, ()> {
+    StableBTreeMap::init(get_virtual_memory(MEMORY_ID_CHANGES_PENDING))
+}
+
+impl Storable for ChangeEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![];
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[allow(clippy::expect_used)] // ? SAFETY
+        ciborium::de::from_reader(&bytes[..]).expect("deserialization must succeed.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Replace the stable memory by the raw dump, only for the offline tool
+#[cfg(feature = "cli")]
+pub fn load_stable_memory(bytes: Vec<u8>) {
+    MEMORY_MANAGER.with(|memory_manager| {
+        *memory_manager.borrow_mut() = MemoryManager::init(std::rc::Rc::new(RefCell::new(bytes)));
+    });
+    STATE.with(|state| *state.borrow_mut() = State::default());
+}
 
 #[allow(unused)]
 pub fn with_state<F, R>(callback: F) -> R
@@ -212,12 +685,92 @@ pub fn must_be_admin() -> Result<(), String> {
     })
 }
 
+/// Reject the reads while the snapshot is importing, the state is not complete
+pub fn must_be_live() -> Result<(), String> {
+    with_state(|s| {
+        if s.importing() {
+            return Err("snapshot is importing".into());
+        }
+        Ok(())
+    })
+}
+
+/// Reject the direct writes on the secondary, its data only comes from the primary
+pub fn must_be_primary() -> Result<(), String> {
+    with_state(|s| match s.settings.get().replication.primary {
+        Some(primary) => Err(format!("this canister is a secondary, write to the primary: {primary}")),
+        None => Ok(()),
+    })
+}
+
+/// Reject the writes while the snapshot is importing or exporting, the administrators must not modify it either
+pub fn must_be_writable() -> Result<(), String> {
+    must_be_live()?;
+    must_be_primary()?;
+    with_state(|s| {
+        if s.exporting() {
+            return Err("snapshot is exporting".into());
+        }
+        Ok(())
+    })
+}
+
 /// Get the current time
 fn now() -> TimestampMills {
     let now = ic_cdk::api::time() as i64;
     now.into()
 }
 
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Convert the nanoseconds of the canister time
+fn to_mills(nanos: u64) -> TimestampMills {
+    ((nanos / NANOS_PER_MILLI) as i64).into()
+}
+
+/// Days since the unix epoch
+fn today() -> u32 {
+    (ic_cdk::api::time() / NANOS_PER_DAY) as u32
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Size of the stable memory
+fn stable_bytes() -> u64 {
+    ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE
+}
+
+const UNIQUE_USERS_KEEP_DAYS: u32 = 90; // Sketches older than this are removed
+
+const MAX_RANK_LIMIT: u64 = 100; // Max items of one page of the leaderboard
+const MAX_COLLECTIONS_LIMIT: u64 = 100; // Max items of one page of the collections
+const MAX_FROZEN_LIMIT: u64 = 100; // Max items of one page of the frozen dapps
+
+const DEFAULT_MAX_PAYLOAD_BYTES: u64 = 1024 * 1024; // Max size of the arguments of the ingress message
+
+const MAX_REPLICATION_LOG: u64 = 100_000; // Max ops waiting for the secondaries
+const MAX_REPLICATION_BATCH_BYTES: usize = 1024 * 1024; // Ops of one push
+
+const FIRST_DATA_MAP: u8 = 10; // The administrators and the settings are not data
+
+const MAX_EXPORT_PAGE_BYTES: usize = 512 * 1024; // Records of one page, the page must be imported in one message
+
+pub const MAX_CANISTER_BATCH: usize = 100; // Max anchors of one call from other canisters
+pub const MAX_BATCH_QUERY: usize = 100; // Max anchors of one batch query
+const MAX_RESOLVE_ITEMS: usize = 100; // Max items of one page of the closure of one dapp
+const MAX_RESOLVE_BYTES: usize = 1024 * 1024; // Items of one page, the response is limited to 2 MiB
+
+const MAX_RECEIPTS_LIMIT: u64 = 100; // Max items of one page of the upload receipts
+
+const MAX_CHANGES: u64 = 1_000_000; // The oldest events are pruned
+const MAX_CHANGES_LIMIT: u64 = 1000; // Max events of one page of the change feed
+const MAX_CHANGES_FLUSH: usize = 1000; // Max counters flushed at once
+
+const MAX_TOKEN_TTL: u64 = 60 * 60 * 1_000_000_000; // Signed tokens must expire within 1 hour
+const MAX_TOKEN_NONCES_PRUNE: usize = 10_000; // Max removed expired nonces of one call
+const MAX_RANK_PRUNE: usize = 10_000; // Max removed items of one prune call
+
 impl State {
     // ================== admin ==================
     fn is_admin(&self, caller: &Principal) -> bool {
@@ -245,262 +798,2930 @@ impl State {
         #[allow(clippy::unwrap_used)] // ? SAFETY
         let id: PublisherParsedId = publisher.anchor.as_ref().as_str().try_into().unwrap();
         #[allow(clippy::unwrap_used)] // ? SAFETY
-        id.check_canister_id(&ic_cdk::id()).unwrap();
+        id.check_canister_id(&self.canister_id()).unwrap();
         let key = &id.id; // key
 
-        self.publisher.insert(key.to_owned(), publisher);
+        let replica = self.inner_replica_upload(EntityKind::Publisher, &publisher);
+        let anchor = publisher.anchor.as_ref().to_string();
+        let action = match self.publisher.insert(key.to_owned(), publisher) {
+            Some(_) => ChangeAction::Update,
+            None => ChangeAction::Insert,
+        };
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Publisher, anchor, action);
     }
     pub fn publisher_query(&self, id: PublisherParsedId) -> Option<Publisher> {
-        id.check_canister_id(&ic_cdk::id()).ok()?;
+        id.check_canister_id(&self.canister_id()).ok()?;
         let key = &id.id; // key
 
         self.publisher.get(key)
     }
+    // ! Administrator or publisher modification
+    pub fn publisher_key_update(
+        &mut self,
+        id: PublisherParsedId,
+        key: Option<PublisherKey>,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id = id.id; // key
 
-    // ================== code ==================
-    // ! Administrator insert
-    pub fn code_update(&mut self, code: CodeData) {
-        #[allow(clippy::unwrap_used)] // ? SAFETY
-        let id: CodeDataParsedId = code.anchor.as_ref().as_str().try_into().unwrap();
-        #[allow(clippy::unwrap_used)] // ? SAFETY
-        id.check_canister_id(&ic_cdk::id()).unwrap();
-        let key = &id.hash; // key
-
-        #[allow(clippy::panic)] // ? SAFETY
+        if !self.publisher.contains_key(&id) {
+            return Err("publisher is missing".into());
+        }
+        if !self.is_admin(caller) && !self.inner_is_publisher_controller(&id, caller) {
+            return Err("Permission is required".into());
+        }
+
+        if let Some(key) = &key {
+            check_public_key(key)?;
+        }
+        let replica = self.inner_replica_record(MEMORY_ID_PUBLISHER_KEYS, &id, key.as_ref());
+        let anchor = self.inner_publisher_anchor(&id);
+        match key {
+            Some(key) => {
+                self.publisher_keys.insert(id, key);
+            }
+            None => {
+                self.publisher_keys.remove(&id);
+            }
+        }
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Publisher, anchor, ChangeAction::Update);
+        Ok(())
+    }
+    pub fn publisher_key_query(&self, id: PublisherParsedId) -> Option<PublisherKey> {
+        id.check_canister_id(&self.canister_id()).ok()?;
+        let key = &id.id; // key
+
+        self.publisher_keys.get(key)
+    }
+    fn inner_publisher_anchor(&self, id: &PublisherId) -> String {
+        self.publisher
+            .get(id)
+            .map(|publisher| publisher.anchor.as_ref().to_string())
+            .unwrap_or_default()
+    }
+    fn inner_is_publisher_controller(&self, id: &PublisherId, caller: &Principal) -> bool {
+        self.publisher_controllers
+            .get(id)
+            .is_some_and(|controller| controller == *caller)
+    }
+    /// The item must be uploaded by the publisher or assigned to it
+    fn inner_owner_check(&self, owned: &OwnedKey, publisher: &PublisherId) -> Result<(), String> {
+        match self.owners.get(owned) {
+            Some(owner) if owner == *publisher => Ok(()),
+            Some(_) => Err("item is uploaded by other publisher".into()),
+            None => Err("item is not assigned to the publisher".into()),
+        }
+    }
+    /// The publisher must be controlled by the caller
+    fn inner_publisher_of_caller(&self, id: &PublisherParsedId, caller: &Principal) -> Result<PublisherId, String> {
+        id.check_canister_id(&self.canister_id())?;
+        if !self.inner_is_publisher_controller(&id.id, caller) {
+            return Err("caller is not the controller of the publisher".into());
+        }
+        Ok(id.id.clone())
+    }
+    // ! Administrator or publisher modification
+    /// The controller can be set by administrators or transferred by the current controller
+    pub fn publisher_controller_update(
+        &mut self,
+        id: PublisherParsedId,
+        controller: Option<Principal>,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id = id.id; // key
+
+        if !self.publisher.contains_key(&id) {
+            return Err("publisher is missing".into());
+        }
+        if !self.is_admin(caller) && !self.inner_is_publisher_controller(&id, caller) {
+            return Err("Permission is required".into());
+        }
+
+        if controller == Some(Principal::anonymous()) {
+            return Err("anonymous user can not be the controller".into());
+        }
+        let replica = self.inner_replica_record(MEMORY_ID_PUBLISHER_CONTROLLERS, &id, controller.as_ref());
+        let anchor = self.inner_publisher_anchor(&id);
+        match controller {
+            Some(controller) => {
+                self.publisher_controllers.insert(id, controller);
+            }
+            None => {
+                self.publisher_controllers.remove(&id);
+            }
+        }
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Publisher, anchor, ChangeAction::Update);
+        Ok(())
+    }
+    pub fn publisher_controller_query(&self, id: PublisherParsedId) -> Option<Principal> {
+        id.check_canister_id(&self.canister_id()).ok()?;
+        let key = &id.id; // key
+
+        self.publisher_controllers.get(key)
+    }
+
+    // ================== quota ==================
+
+    fn inner_publisher_quota(&self, publisher: &PublisherId) -> QuotaLimit {
+        self.publisher_quotas
+            .get(publisher)
+            .unwrap_or_else(|| self.settings.get().quota)
+    }
+    /// Count the new item into the usage of the publisher, the quota is checked if required
+    fn inner_usage_insert(
+        &mut self,
+        publisher: &PublisherId,
+        owned: OwnedKey,
+        size: u64,
+        check: bool,
+    ) -> Result<(), String> {
+        if check {
+            self.inner_usage_check(publisher, None, size)?;
+        }
+        let mut usage = self.publisher_usage.get(publisher).unwrap_or_default();
+        usage.bytes = usage.bytes.saturating_add(size);
+        usage.items = usage.items.saturating_add(1);
+        self.publisher_usage.insert(publisher.clone(), usage);
+        self.owners.insert(owned, publisher.clone());
+        Ok(())
+    }
+    /// The item of the publisher is replaced, the quota is checked if required
+    fn inner_usage_replace(&mut self, publisher: &PublisherId, old: u64, new: u64, check: bool) -> Result<(), String> {
+        if check {
+            self.inner_usage_check(publisher, Some(old), new)?;
+        }
+        let mut usage = self.publisher_usage.get(publisher).unwrap_or_default();
+        usage.bytes = usage.bytes.saturating_sub(old).saturating_add(new);
+        self.publisher_usage.insert(publisher.clone(), usage);
+        Ok(())
+    }
+    /// Check the quota before the new item is stored or the old item is replaced, the bytes are checked if the item grows
+    fn inner_usage_check(&self, publisher: &PublisherId, old: Option<u64>, new: u64) -> Result<(), String> {
+        let usage = self.publisher_usage.get(publisher).unwrap_or_default();
+        let quota = self.inner_publisher_quota(publisher);
+        if old.is_none() && quota.max_items < usage.items.saturating_add(1) {
+            return Err(format!("quota of items is exceeded: {}", quota.max_items));
+        }
+        let old = old.unwrap_or_default();
+        if old < new && quota.max_bytes < usage.bytes.saturating_sub(old).saturating_add(new) {
+            return Err(format!("quota of bytes is exceeded: {}", quota.max_bytes));
+        }
+        Ok(())
+    }
+    // ! Administrator modification
+    pub fn publisher_quota_update(&mut self, id: PublisherParsedId, quota: Option<QuotaLimit>) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id = id.id; // key
+
+        if !self.publisher.contains_key(&id) {
+            return Err("publisher is missing".into());
+        }
+
+        match quota {
+            Some(quota) => {
+                self.publisher_quotas.insert(id, quota);
+            }
+            None => {
+                self.publisher_quotas.remove(&id);
+            }
+        }
+        Ok(())
+    }
+    // ! Administrator modification
+    pub fn quota_default_update(&mut self, quota: QuotaLimit) {
+        let mut settings = self.settings.get().to_owned();
+        settings.quota = quota;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
+    }
+    pub fn publisher_usage(&self, id: PublisherParsedId) -> Option<PublisherUsageView> {
+        id.check_canister_id(&self.canister_id()).ok()?;
+        let key = &id.id; // key
+
+        if !self.publisher.contains_key(key) {
+            return None;
+        }
+
+        Some(PublisherUsageView {
+            usage: self.publisher_usage.get(key).unwrap_or_default(),
+            quota: self.inner_publisher_quota(key),
+        })
+    }
+
+    // ================== health ==================
+
+    pub fn health(&self) -> HealthView {
+        let limits = self.settings.get().limits;
+        let cycles = ic_cdk::api::canister_balance128();
+        let stable_bytes = stable_bytes();
+        let cycles_low = limits.min_cycles.is_some_and(|min| cycles < min);
+        let storage_full = limits.max_stable_bytes.is_some_and(|max| max <= stable_bytes);
+        let importing = self.importing();
+        let exporting = self.exporting();
+        HealthView {
+            cycles,
+            stable_bytes,
+            limits,
+            cycles_low,
+            storage_full,
+            importing,
+            exporting,
+            uploads_allowed: !cycles_low && !storage_full && !importing && !exporting,
+        }
+    }
+    /// New uploads are rejected if cycles or stable memory cross the limits.
+    /// Queries and counters are not affected
+    pub fn upload_check(&self) -> Result<(), String> {
+        let health = self.health();
+        if health.cycles_low {
+            return Err(format!("uploads are paused: cycles are low: {}", health.cycles));
+        }
+        if health.storage_full {
+            return Err(format!(
+                "uploads are paused: stable memory is full: {}",
+                health.stable_bytes
+            ));
+        }
+        if health.importing {
+            return Err("uploads are paused: snapshot is importing".into());
+        }
+        if health.exporting {
+            return Err("uploads are paused: snapshot is exporting".into());
+        }
+        Ok(())
+    }
+    // ! Administrator modification
+    pub fn upload_limits_update(&mut self, limits: UploadLimits) {
+        let mut settings = self.settings.get().to_owned();
+        settings.limits = limits;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
+    }
+
+    // ================== replication ==================
+
+    /// The anchors are issued for the primary if this canister is a secondary,
+    /// or for the exporting canister if the state is imported from it
+    fn canister_id(&self) -> Principal {
+        let settings = self.settings.get();
+        settings
+            .replication
+            .primary
+            .or(settings.origin)
+            .unwrap_or_else(ic_cdk::id)
+    }
+    /// The op is only made if there are secondaries, the owner is counted by them too
+    fn inner_replica_upload<T: Serialize>(
+        &self,
+        kind: EntityKind,
+        item: &T,
+        owner: Option<&PublisherId>,
+    ) -> Option<ReplicaOp> {
+        if self.settings.get().replication.secondaries.is_empty() {
+            return None;
+        }
+        serde_json::to_string(item).ok().map(|json| ReplicaOp::Upload {
+            kind,
+            json,
+            owner: owner.map(|owner| owner.to_bytes().to_vec()),
+        })
+    }
+    /// The secondaries add the same delta, so the periods are counted as the primary
+    fn inner_replica_counter(&self, kind: CounterKind, item: &[u8], old: u64, new: u64) -> Option<ReplicaOp> {
+        if self.settings.get().replication.secondaries.is_empty() || old == new {
+            return None;
+        }
+        Some(ReplicaOp::Counter {
+            kind,
+            item: item.to_vec(),
+            delta: (new as i64).saturating_sub(old as i64),
+        })
+    }
+    fn inner_replica_counter_set(&self, kind: CounterKind, item: &[u8], value: u64) -> Option<ReplicaOp> {
+        if self.settings.get().replication.secondaries.is_empty() {
+            return None;
+        }
+        Some(ReplicaOp::CounterSet {
+            kind,
+            item: item.to_vec(),
+            value,
+        })
+    }
+    /// The record as it is stored, for the changes without the uploaded item
+    fn inner_replica_record<K: Storable, V: Storable>(&self, map: u8, key: &K, value: Option<&V>) -> Option<ReplicaOp> {
+        if self.settings.get().replication.secondaries.is_empty() {
+            return None;
+        }
+        Some(ReplicaOp::Record {
+            map,
+            key: key.to_bytes().to_vec(),
+            value: value.map(|value| value.to_bytes().to_vec()),
+        })
+    }
+    fn inner_counter_get(&self, kind: CounterKind, item: &[u8]) -> Option<u64> {
+        match kind {
+            CounterKind::DappCalled => self.dapp_called.get(&WrappedDappId::from_bytes(Cow::Borrowed(item))),
+            CounterKind::DappAccessed => self.dapp_accessed.get(&WrappedDappId::from_bytes(Cow::Borrowed(item))),
+            CounterKind::DappCollected => self.dapp_collected.get(&WrappedDappId::from_bytes(Cow::Borrowed(item))),
+            CounterKind::CombinedCalled => self.combined_called.get(&CombinedHash::from_bytes(Cow::Borrowed(item))),
+        }
+    }
+    fn inner_counter_insert(&mut self, kind: CounterKind, item: &[u8], value: u64) -> Option<u64> {
+        match self.inner_dapp_counter(kind) {
+            Some(counter) => counter.insert(WrappedDappId::from_bytes(Cow::Borrowed(item)), value),
+            None => self
+                .combined_called
+                .insert(CombinedHash::from_bytes(Cow::Borrowed(item)), value),
+        }
+    }
+    fn inner_replicate(&mut self, op: Option<ReplicaOp>) {
+        let Some(op) = op else {
+            return;
+        };
+        let seq = *self.replication_seq.get();
+        self.replication_log.insert(seq, op);
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.replication_seq.set(seq + 1).unwrap();
+        // The secondaries too far behind must be seeded by the snapshot again
+        if MAX_REPLICATION_LOG < self.replication_log.len() {
+            if let Some((first, _)) = self.replication_log.first_key_value() {
+                self.replication_log.remove(&first);
+            }
+        }
+    }
+    /// The limits of the secondary are not checked, the stalled replication can not be fixed by the primary
+    fn inner_replica_upload_apply(
+        &mut self,
+        kind: EntityKind,
+        json: &str,
+        owner: Option<&PublisherId>,
+    ) -> Result<(), String> {
+        match kind {
+            EntityKind::Publisher => {
+                let publisher: Publisher =
+                    serde_json::from_str(json).map_err(|err| format!("wrong publisher: {err}"))?;
+                let id: PublisherParsedId = publisher.anchor.as_ref().as_str().try_into()?;
+                id.check_canister_id(&self.canister_id())?;
+                self.publisher_update(publisher);
+                Ok(())
+            }
+            EntityKind::Code => {
+                let code = serde_json::from_str(json).map_err(|err| format!("wrong code: {err}"))?;
+                self.inner_code_update(code, owner, false)
+            }
+            EntityKind::Api => {
+                let api = serde_json::from_str(json).map_err(|err| format!("wrong api: {err}"))?;
+                self.inner_apis_update(api, owner, false)
+            }
+            EntityKind::Combined => {
+                let combined = serde_json::from_str(json).map_err(|err| format!("wrong combined: {err}"))?;
+                self.inner_combined_update(combined, owner, false)
+            }
+            EntityKind::Dapp => {
+                let dapp = serde_json::from_str(json).map_err(|err| format!("wrong dapp: {err}"))?;
+                self.inner_dapp_update(dapp, owner, false)
+            }
+        }
+    }
+    fn inner_replica_apply(&mut self, op: ReplicaOp) -> Result<(), String> {
+        match op {
+            ReplicaOp::Upload { kind, json, owner } => {
+                let owner = owner.map(|owner| PublisherId::from_bytes(Cow::Owned(owner)));
+                self.inner_replica_upload_apply(kind, &json, owner.as_ref())
+            }
+            ReplicaOp::Counter { kind, item, delta } => {
+                let old = self
+                    .inner_counter_get(kind, &item)
+                    .ok_or_else(|| format!("counter is missing: {kind:?}"))?;
+                let new = old.saturating_add_signed(delta);
+                self.inner_counter_insert(kind, &item, new);
+                self.inner_change_counter(kind, &item);
+                self.inner_rank_set(kind, item.clone(), Some(old), new);
+                self.inner_rank_period_add(kind, item, new.saturating_sub(old));
+                Ok(())
+            }
+            ReplicaOp::CounterSet { kind, item, value } => {
+                let old = self.inner_counter_insert(kind, &item, value);
+                self.inner_change_counter(kind, &item);
+                self.inner_rank_set(kind, item, old, value);
+                Ok(())
+            }
+            ReplicaOp::Record { map, key, value } => {
+                if map < FIRST_DATA_MAP {
+                    return Err(format!("map can not be replicated: {map}"));
+                }
+                let map = self
+                    .snapshot_map_mut(map)
+                    .ok_or_else(|| format!("unknown map: {map}"))?;
+                match value {
+                    Some(value) => map.import(&key, &value),
+                    None => {
+                        map.remove(&key);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+    // ! Administrator modification
+    /// New secondaries must be seeded by the snapshot, then they receive the ops after now
+    pub fn replication_update(&mut self, replication: Replication) {
+        let seq = *self.replication_seq.get();
+        let keep: Vec<Principal> = replication
+            .secondaries
+            .iter()
+            .chain(replication.primary.iter())
+            .copied()
+            .collect();
+        let removed: Vec<Principal> = self
+            .replicas
+            .iter()
+            .map(|(replica, _)| replica)
+            .filter(|replica| !keep.contains(replica))
+            .collect();
+        for replica in removed {
+            self.replicas.remove(&replica);
+        }
+        for secondary in &replication.secondaries {
+            if !self.replicas.contains_key(secondary) {
+                let status = ReplicaStatus {
+                    next: seq,
+                    ..Default::default()
+                };
+                self.replicas.insert(*secondary, status);
+            }
+        }
+        if replication.secondaries.is_empty() {
+            self.replication_log.clear_new();
+        }
+
+        let mut settings = self.settings.get().to_owned();
+        settings.replication = replication;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
+    }
+    pub fn replication_status(&self) -> Vec<(Principal, ReplicaStatus)> {
+        self.replicas.iter().collect()
+    }
+    /// Ops to push to every secondary
+    pub fn replication_batches(&mut self) -> Vec<(Principal, u64, Vec<ReplicaOp>)> {
+        let first = self
+            .replication_log
+            .first_key_value()
+            .map(|(seq, _)| seq)
+            .unwrap_or_else(|| *self.replication_seq.get());
+        let mut batches = vec![];
+        for secondary in self.settings.get().replication.secondaries.clone() {
+            let mut status = self.replicas.get(&secondary).unwrap_or_default();
+            if status.next < first {
+                status.error = Some(format!("ops before {first} are dropped, seed it by the snapshot"));
+                self.replicas.insert(secondary, status);
+                continue;
+            }
+            let mut bytes = 0;
+            let ops: Vec<ReplicaOp> = self
+                .replication_log
+                .range(status.next..)
+                .map(|(_, op)| op)
+                .take_while(|op| {
+                    let first = bytes == 0;
+                    bytes += match op {
+                        ReplicaOp::Upload { json, owner, .. } => json.len() + owner.as_ref().map_or(0, |o| o.len()),
+                        ReplicaOp::Counter { item, .. } => item.len() + 16,
+                        ReplicaOp::CounterSet { item, .. } => item.len() + 8,
+                        ReplicaOp::Record { key, value, .. } => 1 + key.len() + value.as_ref().map_or(0, |v| v.len()),
+                    };
+                    first || bytes <= MAX_REPLICATION_BATCH_BYTES // At least one op
+                })
+                .collect();
+            if !ops.is_empty() {
+                batches.push((secondary, status.next, ops));
+            }
+        }
+        batches
+    }
+    /// Record the result of the push, the ops pushed to all secondaries are removed.
+    /// The secondary stops at the failed op, it is pushed again until it is fixed
+    pub fn replication_pushed(&mut self, secondary: Principal, end: u64, result: Result<u64, String>) {
+        let mut status = self.replicas.get(&secondary).unwrap_or_default();
+        match result {
+            Ok(next) if next < end => {
+                status.next = next;
+                status.failures += 1;
+                status.error = Some(format!("secondary stopped at op {next}"));
+            }
+            Ok(next) => {
+                status.next = next; // Maybe rewound by the secondary
+                status.failures = 0;
+                status.error = None;
+            }
+            Err(err) => {
+                status.failures += 1;
+                status.error = Some(err);
+            }
+        }
+        self.replicas.insert(secondary, status);
+
+        let pushed = self
+            .settings
+            .get()
+            .replication
+            .secondaries
+            .iter()
+            .map(|secondary| self.replicas.get(secondary).map(|s| s.next).unwrap_or_default())
+            .min()
+            .unwrap_or_else(|| *self.replication_seq.get());
+        while let Some((seq, _)) = self.replication_log.first_key_value() {
+            if pushed <= seq {
+                break;
+            }
+            self.replication_log.remove(&seq);
+        }
+    }
+    // ! Primary call
+    /// Apply the ops in order until one fails, the next expected op is returned.
+    /// The applied ops are skipped, so the retries are harmless
+    pub fn replicate_apply(&mut self, caller: &Principal, from: u64, ops: Vec<ReplicaOp>) -> Result<u64, String> {
+        if self.settings.get().replication.primary != Some(*caller) {
+            return Err("caller is not the primary".into());
+        }
+        if self.importing() || self.exporting() {
+            return Err("snapshot is importing or exporting".into());
+        }
+        let mut status = self.replicas.get(caller).unwrap_or(ReplicaStatus {
+            next: from, // The first push after seeded
+            ..Default::default()
+        });
+        if status.next < from {
+            return Ok(status.next); // Some ops are missing, the primary should push again from here
+        }
+        for (seq, op) in (from..).zip(ops) {
+            if seq < status.next {
+                continue;
+            }
+            if let Err(err) = self.inner_replica_apply(op) {
+                status.failures += 1;
+                status.error = Some(format!("op {seq} failed: {err}"));
+                break; // The later ops may depend on it
+            }
+            status.next = seq + 1;
+            status.failures = 0;
+            status.error = None;
+        }
+        let next = status.next;
+        self.replicas.insert(*caller, status);
+        Ok(next)
+    }
+
+    // ================== snapshot ==================
+
+    /// Every map of the state, ordered by the MemoryId
+    fn snapshot_maps(&self) -> Vec<(u8, &'static str, &dyn SnapshotMap)> {
+        vec![
+            (MEMORY_ID_ADMIN, "admin", &self.admin),
+            (MEMORY_ID_SETTINGS, "settings", &self.settings),
+            (MEMORY_ID_PUBLISHER, "publisher", &self.publisher),
+            (MEMORY_ID_PUBLISHER_KEYS, "publisher_keys", &self.publisher_keys),
+            (
+                MEMORY_ID_PUBLISHER_CONTROLLERS,
+                "publisher_controllers",
+                &self.publisher_controllers,
+            ),
+            (MEMORY_ID_OWNERS, "owners", &self.owners),
+            (MEMORY_ID_PUBLISHER_QUOTAS, "publisher_quotas", &self.publisher_quotas),
+            (MEMORY_ID_PUBLISHER_USAGE, "publisher_usage", &self.publisher_usage),
+            (MEMORY_ID_UPLOAD_RECEIPTS, "upload_receipts", &self.upload_receipts),
+            (MEMORY_ID_CODE, "code", &self.code),
+            (MEMORY_ID_APIS, "apis", &self.apis),
+            (MEMORY_ID_COMBINED, "combined", &self.combined),
+            (MEMORY_ID_COMBINED_CALLED, "combined_called", &self.combined_called),
+            (MEMORY_ID_DAPP, "dapp", &self.dapp),
+            (MEMORY_ID_DAPP_ACCESSES, "dapp_accesses", &self.dapp_accesses),
+            (MEMORY_ID_DAPP_ACCESSED, "dapp_accessed", &self.dapp_accessed),
+            (MEMORY_ID_DAPP_CALLED, "dapp_called", &self.dapp_called),
+            (MEMORY_ID_DAPP_COLLECTED, "dapp_collected", &self.dapp_collected),
+            (MEMORY_ID_DAPP_UNIQUE, "dapp_unique", &self.dapp_unique),
+            (MEMORY_ID_DAPP_COLLECTIONS, "dapp_collections", &self.dapp_collections),
+            (MEMORY_ID_DAPP_FROZEN, "dapp_frozen", &self.dapp_frozen),
+            (
+                MEMORY_ID_DAPP_ACCESS_RULES,
+                "dapp_access_rules",
+                &self.dapp_access_rules,
+            ),
+            (MEMORY_ID_TOKEN_NONCES, "token_nonces", &self.token_nonces),
+            (MEMORY_ID_RANKS, "ranks", &self.ranks),
+            (MEMORY_ID_PERIOD_COUNTERS, "period_counters", &self.period_counters),
+        ]
+    }
+    pub fn exporting(&self) -> bool {
+        self.export.get().exporting
+    }
+    // ! Administrator call
+    /// Freeze the state and start the export from the first page, the export in progress is restarted
+    pub fn state_export_begin(&mut self) -> Result<(), String> {
+        if self.importing() {
+            return Err("snapshot is importing".into());
+        }
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.export
+            .set(ExportProgress {
+                exporting: true,
+                created: ic_cdk::api::time(),
+                next: Some(ExportCursor::default()),
+                last: None,
+            })
+            .unwrap();
+        Ok(())
+    }
+    // ! Administrator call
+    /// Unfreeze the state without exporting the rest pages
+    pub fn state_export_end(&mut self) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.export.set(Default::default()).unwrap();
+        self.inner_receipts_flush();
+    }
+    pub fn state_export_status(&self) -> ExportProgress {
+        self.export.get().to_owned()
+    }
+    // ! Administrator call
+    /// Export the page of the cursor issued by the canister, the last page can be exported again if it is lost.
+    /// The state is frozen until the last page is exported, so the pages are one consistent snapshot
+    pub fn state_export(&mut self, cursor: Option<ExportCursor>) -> Result<ExportPage, String> {
+        let mut progress = self.export.get().to_owned();
+        if !progress.exporting {
+            return Err("export is not begun".into());
+        }
+        // Only the cursors issued by the canister are accepted, the keys and the checksum are never from the client
+        let cursor = cursor.unwrap_or_default();
+        let again = progress.last.as_ref() == Some(&cursor);
+        if !again && progress.next.as_ref() != Some(&cursor) {
+            return Err(format!(
+                "wrong cursor: expect page {}",
+                progress.next.as_ref().map(|next| next.page).unwrap_or_default()
+            ));
+        }
+
+        let maps = self.snapshot_maps();
+        let manifest = (cursor.page == 0).then(|| SnapshotManifest {
+            canister: ic_cdk::id(),
+            created: progress.created,
+            maps: maps
+                .iter()
+                .map(|(id, name, map)| SnapshotMapInfo {
+                    id: *id,
+                    name: name.to_string(),
+                    entries: map.entries(),
+                })
+                .collect(),
+        });
+
+        let mut records = vec![];
+        let mut budget = MAX_EXPORT_PAGE_BYTES;
+        let mut next = None;
+        for (id, _, map) in maps.iter().filter(|(id, _, _)| cursor.map <= *id) {
+            let mut after = if *id == cursor.map { cursor.after.clone() } else { None };
+            if !map.export(*id, &mut after, &mut budget, &mut records) {
+                next = Some((*id, after));
+                break;
+            }
+        }
+
+        let page = SnapshotPage {
+            version: SNAPSHOT_VERSION,
+            page: cursor.page,
+            manifest,
+            records,
+            last: next.is_none(),
+        };
+        let mut data = vec![];
+        ciborium::ser::into_writer(&page, &mut data).map_err(|err| format!("encode page failed: {err}"))?;
+        let checksum = snapshot_checksum(&cursor.checksum, &data);
+        let next = next.map(|(map, after)| ExportCursor {
+            page: cursor.page + 1,
+            map,
+            after,
+            checksum: checksum.clone(),
+        });
+
+        if !again {
+            progress.exporting = next.is_some(); // Unfreeze after the last page
+            progress.last = Some(cursor);
+            progress.next = next.clone();
+            #[allow(clippy::unwrap_used)] // ? SAFETY
+            self.export.set(progress).unwrap();
+            self.inner_receipts_flush();
+        }
+
+        Ok(ExportPage { next, data, checksum })
+    }
+
+    fn snapshot_map_mut(&mut self, id: u8) -> Option<&mut dyn SnapshotMap> {
+        Some(match id {
+            MEMORY_ID_SETTINGS => &mut self.settings,
+            MEMORY_ID_PUBLISHER => &mut self.publisher,
+            MEMORY_ID_PUBLISHER_KEYS => &mut self.publisher_keys,
+            MEMORY_ID_PUBLISHER_CONTROLLERS => &mut self.publisher_controllers,
+            MEMORY_ID_OWNERS => &mut self.owners,
+            MEMORY_ID_PUBLISHER_QUOTAS => &mut self.publisher_quotas,
+            MEMORY_ID_PUBLISHER_USAGE => &mut self.publisher_usage,
+            MEMORY_ID_UPLOAD_RECEIPTS => &mut self.upload_receipts,
+            MEMORY_ID_CODE => &mut self.code,
+            MEMORY_ID_APIS => &mut self.apis,
+            MEMORY_ID_COMBINED => &mut self.combined,
+            MEMORY_ID_COMBINED_CALLED => &mut self.combined_called,
+            MEMORY_ID_DAPP => &mut self.dapp,
+            MEMORY_ID_DAPP_ACCESSES => &mut self.dapp_accesses,
+            MEMORY_ID_DAPP_ACCESSED => &mut self.dapp_accessed,
+            MEMORY_ID_DAPP_CALLED => &mut self.dapp_called,
+            MEMORY_ID_DAPP_COLLECTED => &mut self.dapp_collected,
+            MEMORY_ID_DAPP_UNIQUE => &mut self.dapp_unique,
+            MEMORY_ID_DAPP_COLLECTIONS => &mut self.dapp_collections,
+            MEMORY_ID_DAPP_FROZEN => &mut self.dapp_frozen,
+            MEMORY_ID_DAPP_ACCESS_RULES => &mut self.dapp_access_rules,
+            MEMORY_ID_TOKEN_NONCES => &mut self.token_nonces,
+            MEMORY_ID_RANKS => &mut self.ranks,
+            MEMORY_ID_PERIOD_COUNTERS => &mut self.period_counters,
+            _ => return None, // The administrators are merged
+        })
+    }
+    /// All records and the entries of every map, used by the offline tool
+    #[cfg(feature = "cli")]
+    pub fn snapshot_all(&self) -> (Vec<SnapshotMapInfo>, Vec<SnapshotRecord>) {
+        let mut maps = vec![];
+        let mut records = vec![];
+        for (id, name, map) in self.snapshot_maps() {
+            maps.push(SnapshotMapInfo {
+                id,
+                name: name.to_string(),
+                entries: map.entries(),
+            });
+            let mut budget = usize::MAX;
+            map.export(id, &mut None, &mut budget, &mut records);
+        }
+        (maps, records)
+    }
+    pub fn importing(&self) -> bool {
+        self.import.get().importing
+    }
+    // ! Administrator call
+    /// Remove the data of one map in one message, the administrators and the settings are kept.
+    /// Return the count of the maps still holding data, call it again until nothing is left
+    pub fn state_wipe(&mut self) -> Result<u64, String> {
+        if self.exporting() {
+            return Err("snapshot is exporting".into());
+        }
+        let ids: Vec<u8> = self
+            .snapshot_maps()
+            .iter()
+            .filter(|(id, _, map)| FIRST_DATA_MAP <= *id && 0 < map.entries())
+            .map(|(id, _, _)| *id)
+            .collect();
+        if let Some(map) = ids.first().and_then(|id| self.snapshot_map_mut(*id)) {
+            map.wipe();
+        }
+        let left = ids.len().saturating_sub(1) as u64;
+        if left == 0 {
+            #[allow(clippy::unwrap_used)] // ? SAFETY
+            self.import.set(Default::default()).unwrap();
+            self.inner_changes_reset();
+        }
+        Ok(left)
+    }
+    // ! Administrator call
+    /// Only the empty state can be imported into
+    pub fn state_import_begin(&mut self) -> Result<(), String> {
+        if self.importing() {
+            return Err("import is already begun".into());
+        }
+        if let Some((_, name, _)) = self
+            .snapshot_maps()
+            .into_iter()
+            .find(|(id, _, map)| FIRST_DATA_MAP <= *id && 0 < map.entries())
+        {
+            return Err(format!("state is not empty: {name}, wipe it first"));
+        }
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.import
+            .set(ImportProgress {
+                importing: true,
+                ..Default::default()
+            })
+            .unwrap();
+        Ok(())
+    }
+    // ! Administrator call
+    /// Import the next page, the checksum must be chained with the last imported page.
+    /// The records are inserted as they are, so counters and leaderboards are restored exactly
+    pub fn state_import(&mut self, data: Vec<u8>, checksum: Vec<u8>) -> Result<(), String> {
+        let mut progress = self.import.get().to_owned();
+        if !progress.importing {
+            return Err("import is not begun".into());
+        }
+        if progress.finished {
+            return Err("the last page is already imported".into());
+        }
+        if snapshot_checksum(&progress.checksum, &data) != checksum {
+            return Err(format!("wrong checksum of page {}", progress.page));
+        }
+
+        let page: SnapshotPage = ciborium::de::from_reader(&data[..]).map_err(|err| format!("wrong page: {err}"))?;
+        if page.version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version: {}", page.version));
+        }
+        if page.page != progress.page {
+            return Err(format!("wrong page: expect {} but {}", progress.page, page.page));
+        }
+        match page.manifest {
+            Some(manifest) => {
+                progress.canister = Some(manifest.canister);
+                progress.expected = manifest.maps.iter().map(|m| (m.id, m.entries)).collect();
+            }
+            None if page.page == 0 => return Err("manifest is missing".into()),
+            None => {}
+        }
+        let known: Vec<u8> = self.snapshot_maps().iter().map(|(id, _, _)| *id).collect();
+        if let Some(record) = page.records.iter().find(|r| !known.contains(&r.map)) {
+            return Err(format!("unknown map: {}", record.map));
+        }
+
+        for record in page.records {
+            if record.map == 0 {
+                // Keep the current administrators
+                let admin = AdminUsers::from_bytes(Cow::Borrowed(&record.value));
+                for user in admin.users {
+                    self.admin_add(user);
+                }
+                continue;
+            }
+            if let Some(map) = self.snapshot_map_mut(record.map) {
+                map.import(&record.key, &record.value)?;
+            }
+        }
+
+        progress.page += 1;
+        progress.checksum = checksum;
+        progress.finished = page.last;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.import.set(progress).unwrap();
+        Ok(())
+    }
+    // ! Administrator call
+    /// Verify the checksum of the last page and the entries in the manifest, then switch it live
+    pub fn state_import_commit(&mut self, checksum: Vec<u8>) -> Result<(), String> {
+        let progress = self.import.get().to_owned();
+        if !progress.importing || !progress.finished {
+            return Err("import is not finished".into());
+        }
+        if progress.checksum != checksum {
+            return Err("checksum of the snapshot is mismatched".into());
+        }
+        for (id, name, map) in self.snapshot_maps() {
+            if id < FIRST_DATA_MAP {
+                continue; // The administrators are merged
+            }
+            let entries = progress
+                .expected
+                .iter()
+                .find(|(expected, _)| *expected == id)
+                .map(|(_, entries)| *entries)
+                .unwrap_or_default(); // The map is missing in the older snapshot
+            if map.entries() != entries {
+                return Err(format!(
+                    "entries of {name} are mismatched: {} != {entries}",
+                    map.entries()
+                ));
+            }
+        }
+        // The anchors in the snapshot are issued by the exporting canister
+        let mut settings = self.settings.get().to_owned();
+        if settings.origin.is_none() && progress.canister.is_some_and(|canister| canister != ic_cdk::id()) {
+            settings.origin = progress.canister;
+            #[allow(clippy::unwrap_used)] // ? SAFETY
+            self.settings.set(settings).unwrap();
+        }
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.import.set(Default::default()).unwrap();
+        self.inner_changes_reset();
+        self.inner_receipts_flush();
+        Ok(())
+    }
+    pub fn state_import_status(&self) -> ImportProgress {
+        self.import.get().to_owned()
+    }
+
+    // ================== stats ==================
+
+    pub fn storage_stats(&self) -> StorageStats {
+        let mut memories: Vec<MemoryStats> = self
+            .snapshot_maps()
+            .into_iter()
+            .map(|(id, name, map)| memory_stats(id, name, map.entries()))
+            .collect();
+        // Not in the snapshot
+        memories.push(memory_stats(MEMORY_ID_IMPORT, "import", 1));
+        memories.push(memory_stats(MEMORY_ID_EXPORT, "export", 1));
+        memories.push(memory_stats(
+            MEMORY_ID_PENDING_RECEIPTS,
+            "pending_receipts",
+            self.pending_receipts.len(),
+        ));
+        memories.push(memory_stats(
+            MEMORY_ID_REPLICATION_LOG,
+            "replication_log",
+            self.replication_log.len(),
+        ));
+        memories.push(memory_stats(MEMORY_ID_REPLICAS, "replicas", self.replicas.len()));
+        memories.push(memory_stats(MEMORY_ID_REPLICATION_SEQ, "replication_seq", 1));
+        memories.push(memory_stats(MEMORY_ID_CHANGES, "changes", self.changes.len()));
+        memories.push(memory_stats(MEMORY_ID_CHANGES_SEQ, "changes_seq", 1));
+        memories.push(memory_stats(
+            MEMORY_ID_CHANGES_PENDING,
+            "changes_pending",
+            self.changes_pending.len(),
+        ));
+        memories.sort_by_key(|m| m.id);
+        let total_pages = memories.iter().map(|m| m.pages).sum::<u64>();
+
+        let publishers = self
+            .publisher_usage
+            .iter()
+            .map(|(id, usage)| PublisherStats {
+                publisher: self
+                    .publisher
+                    .get(&id)
+                    .map(|p| p.anchor.as_ref().to_string())
+                    .unwrap_or_default(),
+                usage,
+            })
+            .collect();
+
+        StorageStats {
+            memories,
+            total_pages,
+            total_bytes: total_pages * WASM_PAGE_SIZE,
+            stable_bytes: stable_bytes(),
+            publishers,
+        }
+    }
+
+    // ================== inspect ==================
+
+    pub fn max_payload_bytes(&self) -> u64 {
+        self.settings
+            .get()
+            .max_payload_bytes
+            .unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES)
+    }
+    // ! Administrator modification
+    pub fn max_payload_bytes_update(&mut self, max: Option<u64>) {
+        let mut settings = self.settings.get().to_owned();
+        settings.max_payload_bytes = max;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
+    }
+
+    // ================== payment ==================
+
+    pub fn payment_config(&self) -> PaymentConfig {
+        self.settings.get().payment.clone()
+    }
+    // ! Administrator modification
+    pub fn payment_config_update(&mut self, payment: PaymentConfig) {
+        let mut settings = self.settings.get().to_owned();
+        settings.payment = payment;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
+    }
+    /// Record the receipt of the upload, the id is returned.
+    /// The snapshot is frozen while exporting or importing, so the receipt is pending until it is unfrozen
+    pub fn upload_receipt_insert(&mut self, mut receipt: UploadReceipt) -> u64 {
+        let id = self.inner_receipt_next_id();
+        receipt.id = id;
+        receipt.created = ic_cdk::api::time();
+        if self.exporting() || self.importing() {
+            self.pending_receipts.insert(id, receipt);
+        } else {
+            self.inner_receipts_flush();
+            self.upload_receipts.insert(id, receipt);
+        }
+        id
+    }
+    /// The refund is recorded into the receipt whether it is pending or not
+    pub fn upload_receipt_refunded(&mut self, id: u64, refund: Result<u64, String>) {
+        let pending = self.pending_receipts.contains_key(&id);
+        let receipts = if pending {
+            &mut self.pending_receipts
+        } else {
+            &mut self.upload_receipts
+        };
+        if let Some(mut receipt) = receipts.get(&id) {
+            match refund {
+                Ok(block) => receipt.refund = Some(block),
+                Err(err) => {
+                    let error = receipt.error.map(|error| format!("{error}, ")).unwrap_or_default();
+                    receipt.error = Some(format!("{error}refund failed: {err}"));
+                }
+            }
+            receipts.insert(id, receipt);
+        }
+    }
+    fn inner_receipt_next_id(&self) -> u64 {
+        [
+            self.upload_receipts.last_key_value(),
+            self.pending_receipts.last_key_value(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(id, _)| id + 1)
+        .max()
+        .unwrap_or_default()
+    }
+    /// Move the pending receipts after the snapshot is unfrozen, the id is changed if the imported receipts hold it
+    fn inner_receipts_flush(&mut self) {
+        if self.exporting() || self.importing() {
+            return;
+        }
+        let pending: Vec<(u64, UploadReceipt)> = self.pending_receipts.iter().collect();
+        for (id, mut receipt) in pending {
+            self.pending_receipts.remove(&id);
+            if self.upload_receipts.contains_key(&id) {
+                receipt.id = self.inner_receipt_next_id();
+            }
+            self.upload_receipts.insert(receipt.id, receipt);
+        }
+    }
+    /// Administrators and the caller of the upload call
+    pub fn upload_receipt_query(&self, id: u64, caller: &Principal) -> Option<UploadReceipt> {
+        self.upload_receipts
+            .get(&id)
+            .filter(|receipt| receipt.caller == *caller || self.is_admin(caller))
+    }
+    // ! Administrator call
+    pub fn upload_receipts_query(&self, offset: u64, limit: u64) -> Vec<UploadReceipt> {
+        self.upload_receipts
+            .iter()
+            .rev() // The latest first
+            .map(|(_, receipt)| receipt)
+            .skip(offset as usize)
+            .take(limit.min(MAX_RECEIPTS_LIMIT) as usize)
+            .collect()
+    }
+
+    // ================== code ==================
+    /// Return the size of the new code, or None if the same code is stored
+    /// The replicated code is not checked, the primary has checked it
+    fn inner_code_check(
+        &self,
+        code: &CodeData,
+        publisher: Option<&PublisherId>,
+        check: bool,
+    ) -> Result<Option<u64>, String> {
+        if check {
+            self.upload_check()?;
+        }
+
+        let id: CodeDataParsedId = code.anchor.as_ref().as_str().try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let key = &id.hash; // key
+
+        // The same content of any owner is not inserted again
         if let Some(c) = self.code.get(key) {
             if c.code != code.code || c.js.trim() != code.js.trim() {
-                panic!(
+                return Err(format!(
                     "code already exists: {:?} {:?} vs {:?} {:?}",
                     c.code, c.js, code.code, code.js
-                );
+                ));
             }
-            return;
+            return Ok(None);
+        }
+
+        let size = code.to_bytes().len() as u64;
+        if let (true, Some(publisher)) = (check, publisher) {
+            self.inner_usage_check(publisher, None, size)?;
+        }
+        Ok(Some(size))
+    }
+    /// The new code is counted into the usage of the publisher
+    fn inner_code_update(
+        &mut self,
+        code: CodeData,
+        publisher: Option<&PublisherId>,
+        check: bool,
+    ) -> Result<(), String> {
+        let size = match self.inner_code_check(&code, publisher, check)? {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let id: CodeDataParsedId = code.anchor.as_ref().as_str().try_into()?;
+        let key = &id.hash; // key
+
+        if let Some(publisher) = publisher {
+            let owned = OwnedKey::new(EntityKind::Code, key.to_bytes().to_vec());
+            self.inner_usage_insert(publisher, owned, size, check)?;
+        }
+        let replica = self.inner_replica_upload(EntityKind::Code, &code, publisher);
+        let anchor = code.anchor.as_ref().to_string();
+        self.code.insert(key.to_owned(), code);
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Code, anchor, ChangeAction::Insert);
+        Ok(())
+    }
+    // ! Administrator insert
+    pub fn code_update(&mut self, code: CodeData) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.inner_code_update(code, None, true).unwrap();
+    }
+    // ! Publisher insert
+    /// Return false if the same code is stored, nothing is changed
+    pub fn publisher_code_update(
+        &mut self,
+        publisher: PublisherParsedId,
+        code: CodeData,
+        caller: &Principal,
+    ) -> Result<bool, String> {
+        let publisher = self.inner_publisher_of_caller(&publisher, caller)?;
+        let stored = self.inner_code_check(&code, Some(&publisher), true)?.is_some();
+        self.inner_code_update(code, Some(&publisher), true)?;
+        Ok(stored)
+    }
+    /// Check the upload before it is charged, return the size to charge or None if the same code is stored
+    pub fn publisher_code_check(
+        &self,
+        publisher: &PublisherParsedId,
+        code: &CodeData,
+        caller: &Principal,
+    ) -> Result<Option<u64>, String> {
+        let publisher = self.inner_publisher_of_caller(publisher, caller)?;
+        self.inner_code_check(code, Some(&publisher), true)
+    }
+    pub fn code_query(&self, id: CodeDataParsedId) -> Option<CodeData> {
+        id.check_canister_id(&self.canister_id()).ok()?;
+        let key = &id.hash; // key
+
+        self.code.get(key)
+    }
+
+    // ================== apis ==================
+
+    /// Return the size of the new api, or None if the same api is stored
+    /// The replicated api is not checked, the primary has checked it
+    fn inner_apis_check(
+        &self,
+        api: &ApiData,
+        publisher: Option<&PublisherId>,
+        check: bool,
+    ) -> Result<Option<u64>, String> {
+        if check {
+            self.upload_check()?;
+        }
+
+        let id: ApiDataParsedId = api.anchor.as_ref().as_str().try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let key = &id.hash; // key
+
+        // The same content of any owner is not inserted again
+        if let Some(a) = self.apis.get(key) {
+            if a.content != api.content {
+                return Err("api already exists".into());
+            }
+            return Ok(None);
+        }
+
+        let size = api.to_bytes().len() as u64;
+        if let (true, Some(publisher)) = (check, publisher) {
+            self.inner_usage_check(publisher, None, size)?;
+        }
+        Ok(Some(size))
+    }
+    /// The new api is counted into the usage of the publisher
+    fn inner_apis_update(&mut self, api: ApiData, publisher: Option<&PublisherId>, check: bool) -> Result<(), String> {
+        let size = match self.inner_apis_check(&api, publisher, check)? {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let id: ApiDataParsedId = api.anchor.as_ref().as_str().try_into()?;
+        let key = &id.hash; // key
+
+        if let Some(publisher) = publisher {
+            let owned = OwnedKey::new(EntityKind::Api, key.to_bytes().to_vec());
+            self.inner_usage_insert(publisher, owned, size, check)?;
+        }
+        let replica = self.inner_replica_upload(EntityKind::Api, &api, publisher);
+        let anchor = api.anchor.as_ref().to_string();
+        self.apis.insert(key.to_owned(), api);
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Api, anchor, ChangeAction::Insert);
+        Ok(())
+    }
+    // ! Administrator insert
+    pub fn apis_update(&mut self, api: ApiData) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.inner_apis_update(api, None, true).unwrap();
+    }
+    // ! Publisher insert
+    /// Return false if the same api is stored, nothing is changed
+    pub fn publisher_apis_update(
+        &mut self,
+        publisher: PublisherParsedId,
+        api: ApiData,
+        caller: &Principal,
+    ) -> Result<bool, String> {
+        let publisher = self.inner_publisher_of_caller(&publisher, caller)?;
+        let stored = self.inner_apis_check(&api, Some(&publisher), true)?.is_some();
+        self.inner_apis_update(api, Some(&publisher), true)?;
+        Ok(stored)
+    }
+    /// Check the upload before it is charged, return the size to charge or None if the same api is stored
+    pub fn publisher_apis_check(
+        &self,
+        publisher: &PublisherParsedId,
+        api: &ApiData,
+        caller: &Principal,
+    ) -> Result<Option<u64>, String> {
+        let publisher = self.inner_publisher_of_caller(publisher, caller)?;
+        self.inner_apis_check(api, Some(&publisher), true)
+    }
+    pub fn apis_query(&self, id: ApiDataParsedId) -> Option<ApiData> {
+        id.check_canister_id(&self.canister_id()).ok()?;
+        let key = &id.hash; // key
+
+        self.apis.get(key)
+    }
+
+    // ================== combined ==================
+
+    fn inner_combined_increment_called(&mut self, key: CombinedHash) -> Result<(), String> {
+        let item = key.to_bytes().to_vec();
+        if let Some((old, new)) = counter_add(&mut self.combined_called, key, 1) {
+            self.inner_counter_changed(CounterKind::CombinedCalled, item, old, new);
+        }
+        Ok(())
+    }
+    fn inner_combined_query(&self, key: CombinedHash) -> Option<Combined> {
+        if let Some(mut combined) = self.combined.get(&key) {
+            combined.called = self.combined_called.get(&key).unwrap_or_default();
+            return Some(combined);
+        }
+        None
+    }
+
+    /// Return the size of the new combined, or None if the same combined is stored.
+    /// The replicated combined is not checked, the primary has checked it
+    fn inner_combined_check(
+        &self,
+        combined: &Combined,
+        publisher: Option<&PublisherId>,
+        check: bool,
+    ) -> Result<Option<u64>, String> {
+        if check {
+            self.upload_check()?;
+        }
+
+        let id: CombinedParsedId = combined.anchor.as_ref().as_str().try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let key = &id.hash; // key
+
+        // The same content of any owner is not inserted again
+        if let Some(o) = self.combined.get(key) {
+            if o.components != combined.components {
+                return Err("combined already exists".into());
+            }
+            return Ok(None);
+        }
+
+        let size = combined.to_bytes().len() as u64;
+        if let (true, Some(publisher)) = (check, publisher) {
+            self.inner_usage_check(publisher, None, size)?;
+        }
+        Ok(Some(size))
+    }
+    /// The new combined is counted into the usage of the publisher
+    fn inner_combined_update(
+        &mut self,
+        combined: Combined,
+        publisher: Option<&PublisherId>,
+        check: bool,
+    ) -> Result<(), String> {
+        let size = match self.inner_combined_check(&combined, publisher, check)? {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let id: CombinedParsedId = combined.anchor.as_ref().as_str().try_into()?;
+        let key = &id.hash; // key
+
+        if let Some(publisher) = publisher {
+            let owned = OwnedKey::new(EntityKind::Combined, key.to_bytes().to_vec());
+            self.inner_usage_insert(publisher, owned, size, check)?;
+        }
+        // The counter of the payload is ignored, use counters_set to change it deliberately
+        if !self.combined_called.contains_key(key) {
+            self.combined_called.insert(key.to_owned(), 0);
+            self.inner_rank_set(CounterKind::CombinedCalled, key.to_bytes().to_vec(), None, 0);
+        }
+        let replica = self.inner_replica_upload(EntityKind::Combined, &combined, publisher);
+        let anchor = combined.anchor.as_ref().to_string();
+        self.combined.insert(key.to_owned(), combined);
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Combined, anchor, ChangeAction::Insert);
+        Ok(())
+    }
+    // ! Administrator insert
+    pub fn combined_update(&mut self, combined: Combined) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.inner_combined_update(combined, None, true).unwrap();
+    }
+    // ! Publisher insert
+    /// Return false if the same combined is stored, nothing is changed
+    pub fn publisher_combined_update(
+        &mut self,
+        publisher: PublisherParsedId,
+        combined: Combined,
+        caller: &Principal,
+    ) -> Result<bool, String> {
+        let publisher = self.inner_publisher_of_caller(&publisher, caller)?;
+        let stored = self.inner_combined_check(&combined, Some(&publisher), true)?.is_some();
+        self.inner_combined_update(combined, Some(&publisher), true)?;
+        Ok(stored)
+    }
+    /// Check the upload before it is charged, return the size to charge or None if the same combined is stored
+    pub fn publisher_combined_check(
+        &self,
+        publisher: &PublisherParsedId,
+        combined: &Combined,
+        caller: &Principal,
+    ) -> Result<Option<u64>, String> {
+        let publisher = self.inner_publisher_of_caller(publisher, caller)?;
+        self.inner_combined_check(combined, Some(&publisher), true)
+    }
+    pub fn combined_increment_called(&mut self, id: CombinedParsedId) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let key = &id.hash; // key
+        self.inner_combined_increment_called(key.to_owned())
+    }
+    // ! Administrator call
+    pub fn combined_query(&self, id: CombinedParsedId) -> Option<Combined> {
+        id.check_canister_id(&self.canister_id()).ok()?;
+        let key = &id.hash; // key
+        self.inner_combined_query(key.to_owned())
+    }
+
+    // ================== dapp ==================
+
+    // The jar can only check these two items of DappAccess, other rules are in dapp_access_rules
+    fn inner_dapp_access_by_timestamp_and_token(
+        &self,
+        key: &WrappedDappId,
+        verified: Option<&DappVerified>,
+    ) -> Result<(), String> {
+        if let Some(access) = self.dapp_accesses.get(key) {
+            if !access.access_by_timestamp_and_token(now(), verified) {
+                return Err(format!("access is deny: {}", key.0.as_ref()));
+            }
+        }
+        Ok(())
+    }
+    fn inner_dapp_counter(&mut self, kind: CounterKind) -> Option<&mut StableBTreeMap<WrappedDappId, u64>> {
+        match kind {
+            CounterKind::DappCalled => Some(&mut self.dapp_called),
+            CounterKind::DappAccessed => Some(&mut self.dapp_accessed),
+            CounterKind::DappCollected => Some(&mut self.dapp_collected),
+            CounterKind::CombinedCalled => None,
+        }
+    }
+    fn inner_dapp_counter_init(&mut self, kind: CounterKind, key: WrappedDappId) {
+        let item = key.to_bytes().to_vec();
+        if let Some(counter) = self.inner_dapp_counter(kind) {
+            if !counter.contains_key(&key) {
+                counter.insert(key, 0);
+                self.inner_rank_set(kind, item, None, 0);
+            }
+        }
+    }
+    fn inner_dapp_counter_add(&mut self, kind: CounterKind, key: WrappedDappId, delta: u64) -> Option<u64> {
+        let item = key.to_bytes().to_vec();
+        let (old, new) = counter_add(self.inner_dapp_counter(kind)?, key, delta)?;
+        self.inner_counter_changed(kind, item, old, new);
+        Some(new)
+    }
+    fn inner_dapp_access_by_principal(&self, key: &WrappedDappId, caller: &Principal) -> Result<(), String> {
+        if let Some(rules) = self.dapp_access_rules.get(key) {
+            if rules.deny.contains(caller) || rules.allow.is_some_and(|allow| !allow.contains(caller)) {
+                return Err(format!("access is deny by principal: {}", caller.to_text()));
+            }
+        }
+        Ok(())
+    }
+    /// Return the publisher who issued the checked token
+    fn inner_dapp_access_by_signed_token(
+        &self,
+        key: &WrappedDappId,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+    ) -> Result<Option<PublisherId>, String> {
+        let issuer = match self.dapp_access_rules.get(key).and_then(|rules| rules.issuer) {
+            Some(issuer) => issuer,
+            None => return Ok(None),
+        };
+        let token = token.ok_or_else(|| "access is deny: signed token is required".to_string())?;
+
+        let dapp: DappParsedId = token.dapp.as_str().try_into()?;
+        if WrappedDappId::from(dapp) != *key {
+            return Err("access is deny: token is issued for other dapp".into());
+        }
+        if token.caller != *caller {
+            return Err("access is deny: token is issued for other caller".into());
+        }
+        check_token_expires(token, ic_cdk::api::time(), MAX_TOKEN_TTL)
+            .map_err(|err| format!("access is deny: {err}"))?;
+
+        let issuer: PublisherParsedId = issuer.as_str().try_into()?;
+        let publisher_key = self
+            .publisher_keys
+            .get(&issuer.id)
+            .ok_or_else(|| "access is deny: publisher key is missing".to_string())?;
+        verify_signed_token(&publisher_key, token).map_err(|err| format!("access is deny: {err}"))?;
+
+        if self.token_nonces.contains_key(&token_nonce_key(key, token)) {
+            return Err("access is deny: token is used".into());
+        }
+        Ok(Some(issuer.id))
+    }
+    /// Record the nonce of the token, so it can not be replayed in update calls
+    fn inner_token_nonce_use(
+        &mut self,
+        key: &WrappedDappId,
+        publisher: &PublisherId,
+        token: &SignedToken,
+        now: u64,
+    ) -> Result<(), String> {
+        token_nonce_use(&mut self.token_nonces, token_nonce_key(key, token), publisher, now)
+    }
+    fn inner_dapp_increment_accessed(&mut self, key: WrappedDappId) -> Result<(), String> {
+        self.inner_dapp_counter_add(CounterKind::DappAccessed, key, 1);
+        Ok(())
+    }
+    fn inner_dapp_increment_called(&mut self, key: WrappedDappId) -> Result<(), String> {
+        self.inner_dapp_counter_add(CounterKind::DappCalled, key, 1);
+        Ok(())
+    }
+    fn inner_dapp_record_unique(&mut self, key: WrappedDappId, caller: &Principal) {
+        if *caller == Principal::anonymous() || !self.dapp.contains_key(&key) {
+            return; // anonymous callers are not distinct users
+        }
+        let day = today();
+
+        // remove the expired sketches
+        let expired: Vec<DappDayKey> = self
+            .dapp_unique
+            .range(DappDayKey(key.clone(), 0)..DappDayKey(key.clone(), day.saturating_sub(UNIQUE_USERS_KEEP_DAYS)))
+            .map(|(k, _)| k)
+            .collect();
+        for k in expired {
+            self.dapp_unique.remove(&k);
+        }
+
+        let day_key = DappDayKey(key, day);
+        let mut unique = self.dapp_unique.get(&day_key).unwrap_or_default();
+        unique.insert(caller);
+        self.dapp_unique.insert(day_key, unique);
+    }
+    #[allow(unused)]
+    fn inner_dapp_query_with_increment_accessed(&mut self, key: WrappedDappId) -> Result<DappView, String> {
+        if let Some(mut dapp) = self.dapp.get(&key) {
+            if dapp.frozen.is_some() {
+                return Err(format!("dapp is frozen: {}", key.0.as_ref()));
+            }
+            dapp.access = self
+                .dapp_accesses
+                .get(&key)
+                .ok_or_else(|| "dapp access is missing".to_string())?;
+            dapp.accessed = self.dapp_accessed.get(&key).unwrap_or_default();
+            dapp.called = self.dapp_called.get(&key).unwrap_or_default();
+            dapp.collected = self.dapp_collected.get(&key).unwrap_or_default();
+            self.inner_dapp_increment_accessed(key)?;
+            return Ok(dapp.into());
+        }
+        Err(format!("dapp is missing: {}", key.0.as_ref()))
+    }
+    fn inner_dapp_query(&self, key: WrappedDappId, admin: bool) -> Result<Dapp, String> {
+        if let Some(mut dapp) = self.dapp.get(&key) {
+            if !admin && dapp.frozen.is_some() {
+                return Err(format!("Frozen: {}", dapp.reason));
+            }
+            dapp.access = self
+                .dapp_accesses
+                .get(&key)
+                .ok_or_else(|| "dapp access is missing".to_string())?;
+            dapp.accessed = self.dapp_accessed.get(&key).unwrap_or_default();
+            dapp.called = self.dapp_called.get(&key).unwrap_or_default();
+            dapp.collected = self.dapp_collected.get(&key).unwrap_or_default();
+            return Ok(dapp);
+        }
+        Err(format!("dapp is missing: {}", key.0.as_ref()))
+    }
+
+    /// The dapp is counted into the usage of the publisher who uploaded it.
+    /// The replicated dapp is not checked, the primary has checked it
+    fn inner_dapp_update(
+        &mut self,
+        mut dapp: Dapp,
+        publisher: Option<&PublisherId>,
+        check: bool,
+    ) -> Result<(), String> {
+        if check {
+            self.upload_check()?;
+        }
+
+        let id: DappParsedId = dapp.id.as_ref().as_str().try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        self.inner_dapp_moderation(&id, &mut dapp);
+
+        // Administrators upload for the owning publisher, only the publisher path checks the quota
+        let old = self.dapp.get(&id).map(|old| old.to_bytes().len() as u64);
+        let size = dapp.to_bytes().len() as u64;
+        let owned = OwnedKey::new(EntityKind::Dapp, id.to_bytes().to_vec());
+        let check = check && publisher.is_some();
+        let owner = publisher.cloned().or_else(|| self.owners.get(&owned));
+        if let Some(owner) = &owner {
+            match old {
+                Some(old) => self.inner_usage_replace(owner, old, size, check)?,
+                None => self.inner_usage_insert(owner, owned, size, check)?,
+            }
+        }
+
+        self.dapp_accesses.insert(id.clone(), dapp.access.to_owned());
+        // The counters of the payload are ignored, use counters_set to change them deliberately
+        for kind in [
+            CounterKind::DappAccessed,
+            CounterKind::DappCalled,
+            CounterKind::DappCollected,
+        ] {
+            self.inner_dapp_counter_init(kind, id.clone());
+        }
+        let replica = self.inner_replica_upload(EntityKind::Dapp, &dapp, owner.as_ref());
+        let anchor = dapp.id.as_ref().to_string();
+        let action = match old {
+            Some(_) => ChangeAction::Update,
+            None => ChangeAction::Insert,
+        };
+        self.dapp.insert(id, dapp);
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Dapp, anchor, action);
+        Ok(())
+    }
+    /// Moderation is only changed by dapp_freeze and dapp_unfreeze, the payload is ignored
+    fn inner_dapp_moderation(&self, key: &WrappedDappId, dapp: &mut Dapp) {
+        match self.dapp_frozen.get(key) {
+            Some(frozen) => {
+                dapp.frozen = Some(to_mills(frozen.frozen));
+                dapp.reason = frozen.reason;
+            }
+            None => {
+                dapp.frozen = None;
+                dapp.reason = String::new();
+            }
+        }
+    }
+    fn inner_dapp_owner(&self, key: &WrappedDappId) -> Option<PublisherId> {
+        self.owners
+            .get(&OwnedKey::new(EntityKind::Dapp, key.to_bytes().to_vec()))
+    }
+    /// Administrators and the controller of the publisher who uploaded the dapp
+    fn inner_can_manage_dapp(&self, key: &WrappedDappId, caller: &Principal) -> bool {
+        self.is_admin(caller)
+            || self
+                .inner_dapp_owner(key)
+                .is_some_and(|publisher| self.inner_is_publisher_controller(&publisher, caller))
+    }
+    // ! Administrator insert
+    pub fn dapp_update(&mut self, dapp: Dapp) {
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.inner_dapp_update(dapp, None, true).unwrap();
+    }
+    // ! Publisher insert
+    /// The new dapp is owned by the publisher, the existing dapp must be owned by it
+    pub fn publisher_dapp_update(
+        &mut self,
+        publisher: PublisherParsedId,
+        dapp: Dapp,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        let publisher = self.inner_publisher_of_caller(&publisher, caller)?;
+
+        let id: DappParsedId = dapp.id.as_ref().as_str().try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+        if self.dapp.contains_key(&id) {
+            let owned = OwnedKey::new(EntityKind::Dapp, id.to_bytes().to_vec());
+            self.inner_owner_check(&owned, &publisher)?;
+        }
+
+        self.inner_dapp_update(dapp, Some(&publisher), true)
+    }
+    /// Check the upload before it is charged, return the size to charge.
+    /// The moderation of the stored dapp is applied to the payload
+    pub fn publisher_dapp_check(
+        &self,
+        publisher: &PublisherParsedId,
+        dapp: &mut Dapp,
+        caller: &Principal,
+    ) -> Result<Option<u64>, String> {
+        let publisher = self.inner_publisher_of_caller(publisher, caller)?;
+        self.upload_check()?;
+
+        let id: DappParsedId = dapp.id.as_ref().as_str().try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+        if self.dapp.contains_key(&id) {
+            let owned = OwnedKey::new(EntityKind::Dapp, id.to_bytes().to_vec());
+            self.inner_owner_check(&owned, &publisher)?;
+        }
+
+        self.inner_dapp_moderation(&id, dapp);
+        let old = self.dapp.get(&id).map(|old| old.to_bytes().len() as u64);
+        let size = dapp.to_bytes().len() as u64;
+        self.inner_usage_check(&publisher, old, size)?;
+        Ok(Some(size))
+    }
+    // ! Administrator modification
+    /// Assign the dapp to the publisher, the usage of the existing dapp is moved without checking the quota
+    pub fn dapp_owner_update(&mut self, id: DappParsedId, publisher: Option<PublisherParsedId>) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        let publisher = match publisher {
+            Some(publisher) => {
+                publisher.check_canister_id(&self.canister_id())?;
+                if !self.publisher.contains_key(&publisher.id) {
+                    return Err("publisher is missing".into());
+                }
+                Some(publisher.id)
+            }
+            None => None,
+        };
+
+        let owned = OwnedKey::new(EntityKind::Dapp, id.to_bytes().to_vec());
+        let size = self.dapp.get(&id).map(|dapp| dapp.to_bytes().len() as u64);
+        let mut replicas = vec![self.inner_replica_record(MEMORY_ID_OWNERS, &owned, publisher.as_ref())];
+        if let Some(old) = self.owners.remove(&owned) {
+            if let (Some(size), Some(mut usage)) = (size, self.publisher_usage.get(&old)) {
+                usage.bytes = usage.bytes.saturating_sub(size);
+                usage.items = usage.items.saturating_sub(1);
+                replicas.push(self.inner_replica_record(MEMORY_ID_PUBLISHER_USAGE, &old, Some(&usage)));
+                self.publisher_usage.insert(old, usage);
+            }
+        }
+        if let Some(publisher) = publisher {
+            if let Some(size) = size {
+                let mut usage = self.publisher_usage.get(&publisher).unwrap_or_default();
+                usage.bytes = usage.bytes.saturating_add(size);
+                usage.items = usage.items.saturating_add(1);
+                replicas.push(self.inner_replica_record(MEMORY_ID_PUBLISHER_USAGE, &publisher, Some(&usage)));
+                self.publisher_usage.insert(publisher.clone(), usage);
+            }
+            self.owners.insert(owned, publisher);
+        }
+        for replica in replicas {
+            self.inner_replicate(replica);
+        }
+        Ok(())
+    }
+    // ! Administrator modification
+    pub fn dapp_increment_called_by_admin(&mut self, id: DappParsedId) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        self.inner_dapp_increment_called(id)
+    }
+    // ! Administrator modification
+    /// Only replace the access, the counters and metadata of the dapp are unchanged
+    pub fn dapp_access_update(&mut self, id: DappParsedId, access: DappAccess) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        if !self.dapp.contains_key(&id) {
+            return Err(format!("dapp is missing: {}", id.0.as_ref()));
+        }
+
+        let replica = self.inner_replica_record(MEMORY_ID_DAPP_ACCESSES, &id, Some(&access));
+        self.dapp_accesses.insert(id.clone(), access);
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Dapp, id.0.as_ref().to_string(), ChangeAction::Update);
+        Ok(())
+    }
+    // ! Administrator modification
+    pub fn dapp_freeze(&mut self, id: DappParsedId, reason: String, moderator: Principal) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        let mut dapp = self
+            .dapp
+            .get(&id)
+            .ok_or_else(|| format!("dapp is missing: {}", id.0.as_ref()))?;
+        let time = ic_cdk::api::time();
+        dapp.frozen = Some(to_mills(time));
+        dapp.reason = reason.clone();
+        let frozen = DappFrozen {
+            anchor: dapp.id.as_ref().to_string(),
+            moderator,
+            reason,
+            frozen: time,
+        };
+        let replicas = [
+            self.inner_replica_record(MEMORY_ID_DAPP_FROZEN, &id, Some(&frozen)),
+            self.inner_replica_record(MEMORY_ID_DAPP, &id, Some(&dapp)),
+        ];
+        self.dapp_frozen.insert(id.clone(), frozen);
+        self.dapp.insert(id.clone(), dapp);
+        for replica in replicas {
+            self.inner_replicate(replica);
+        }
+        self.inner_change(EntityKind::Dapp, id.0.as_ref().to_string(), ChangeAction::Freeze);
+        Ok(())
+    }
+    // ! Administrator modification
+    pub fn dapp_unfreeze(&mut self, id: DappParsedId) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        let mut dapp = self
+            .dapp
+            .get(&id)
+            .ok_or_else(|| format!("dapp is missing: {}", id.0.as_ref()))?;
+        dapp.frozen = None;
+        dapp.reason = String::new();
+        let replicas = [
+            self.inner_replica_record::<_, DappFrozen>(MEMORY_ID_DAPP_FROZEN, &id, None),
+            self.inner_replica_record(MEMORY_ID_DAPP, &id, Some(&dapp)),
+        ];
+        self.dapp_frozen.remove(&id);
+        self.dapp.insert(id.clone(), dapp);
+        for replica in replicas {
+            self.inner_replicate(replica);
+        }
+        self.inner_change(EntityKind::Dapp, id.0.as_ref().to_string(), ChangeAction::Unfreeze);
+        Ok(())
+    }
+    // ! Administrator call
+    pub fn dapp_query_frozen(&self, offset: u64, limit: u64) -> Vec<DappFrozen> {
+        self.dapp_frozen
+            .iter()
+            .map(|(_, frozen)| frozen)
+            .skip(offset as usize)
+            .take(limit.min(MAX_FROZEN_LIMIT) as usize)
+            .collect()
+    }
+    // ! Administrator call
+    pub fn dapp_query_by_admin(&self, id: DappParsedId) -> Result<Dapp, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+        self.inner_dapp_query(id, true) // Do not increase accessed
+    }
+
+    /// Ordinary user calls, query the permissions required
+    pub fn dapp_query_access(&self, id: DappParsedId) -> Result<DappAccessView, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        let access = self
+            .dapp_accesses
+            .get(&id)
+            .ok_or_else(|| "dapp access is missing".to_string())?;
+        Ok(access.into())
+    }
+    /// Ordinary users call, pay attention to only the permissions verification of Duration and Token
+    pub fn dapp_increment_called_by_token(
+        &mut self,
+        id: DappParsedId,
+        verified: Option<DappVerified>,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        // ! Check the access permissions
+        self.inner_dapp_access_by_timestamp_and_token(&id, verified.as_ref())?;
+        self.inner_dapp_access_by_principal(&id, caller)?;
+        if let (Some(publisher), Some(token)) = (self.inner_dapp_access_by_signed_token(&id, token, caller)?, token) {
+            self.inner_token_nonce_use(&id, &publisher, token, ic_cdk::api::time())?;
+        }
+
+        self.inner_dapp_record_unique(id.clone(), caller);
+        self.inner_dapp_increment_called(id)
+    }
+    /// Ordinary users call, pay attention to only the permissions verification of Duration and Token
+    pub fn dapp_query_by_token(
+        &self,
+        id: DappParsedId,
+        verified: Option<DappVerified>,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+    ) -> Result<DappView, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        // ! Check the access permissions, the nonce of the token can not be recorded in queries
+        self.inner_dapp_access_by_timestamp_and_token(&id, verified.as_ref())?;
+        self.inner_dapp_access_by_principal(&id, caller)?;
+        self.inner_dapp_access_by_signed_token(&id, token, caller)?;
+
+        self.inner_dapp_query(id, false).map(|dapp| dapp.into()) // Do not increase accessed
+    }
+    /// Ordinary users call, check the access permissions of the canister,
+    /// and return the requirements that must be checked by calling other canisters
+    pub fn dapp_access_requirements(
+        &self,
+        id: &DappParsedId,
+        verified: Option<&DappVerified>,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+    ) -> Result<Vec<BalanceRequirement>, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        // ! Check the access permissions
+        self.inner_dapp_access_by_timestamp_and_token(&id, verified)?;
+        self.inner_dapp_access_by_principal(&id, caller)?;
+        self.inner_dapp_access_by_signed_token(&id, token, caller)?;
+
+        let rules = self.dapp_access_rules.get(&id).unwrap_or_default();
+        Ok(rules.balance.into_iter().collect())
+    }
+    // ! Administrator or publisher modification
+    pub fn dapp_access_rules_update(
+        &mut self,
+        id: DappParsedId,
+        rules: DappAccessRules,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        if !self.dapp.contains_key(&id) {
+            return Err(format!("dapp is missing: {}", id.0.as_ref()));
+        }
+        if !self.inner_can_manage_dapp(&id, caller) {
+            return Err("Permission is required".into());
+        }
+        if let Some(issuer) = &rules.issuer {
+            let issuer: PublisherParsedId = issuer.as_str().try_into()?;
+            issuer.check_canister_id(&self.canister_id())?;
         }
 
-        self.code.insert(key.to_owned(), code);
+        let replica = self.inner_replica_record(MEMORY_ID_DAPP_ACCESS_RULES, &id, Some(&rules));
+        let anchor = id.0.as_ref().to_string();
+        self.dapp_access_rules.insert(id, rules);
+        self.inner_replicate(replica);
+        self.inner_change(EntityKind::Dapp, anchor, ChangeAction::Update);
+        Ok(())
     }
-    pub fn code_query(&self, id: CodeDataParsedId) -> Option<CodeData> {
-        id.check_canister_id(&ic_cdk::id()).ok()?;
-        let key = &id.hash; // key
+    /// Administrators and the publisher of the dapp call, the lists of principals are private
+    pub fn dapp_access_rules_query(&self, id: DappParsedId, caller: &Principal) -> Result<DappAccessRules, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
 
-        self.code.get(key)
+        if !self.dapp.contains_key(&id) {
+            return Err(format!("dapp is missing: {}", id.0.as_ref()));
+        }
+        if !self.inner_can_manage_dapp(&id, caller) {
+            return Err("Permission is required".into());
+        }
+
+        Ok(self.dapp_access_rules.get(&id).unwrap_or_default())
     }
+    /// Ordinary users call, collect the dapp and return the collected count
+    pub fn dapp_collect(&mut self, id: DappParsedId, caller: &Principal) -> Result<u64, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
 
-    // ================== apis ==================
+        if *caller == Principal::anonymous() {
+            return Err("anonymous user can not collect".into());
+        }
+        if !self.dapp.contains_key(&id) {
+            return Err(format!("dapp is missing: {}", id.0.as_ref()));
+        }
 
-    // ! Administrator insert
-    pub fn apis_update(&mut self, api: ApiData) {
-        #[allow(clippy::unwrap_used)] // ? SAFETY
-        let id: ApiDataParsedId = api.anchor.as_ref().as_str().try_into().unwrap();
-        #[allow(clippy::unwrap_used)] // ? SAFETY
-        id.check_canister_id(&ic_cdk::id()).unwrap();
-        let key = &id.hash; // key
+        let key = UserCollectionKey::new(*caller, &id);
+        if !self.dapp_collections.contains_key(&key) {
+            let time = ic_cdk::api::time();
+            let replica = self.inner_replica_record(MEMORY_ID_DAPP_COLLECTIONS, &key, Some(&time));
+            self.dapp_collections.insert(key, time);
+            self.inner_replicate(replica);
+            self.inner_dapp_counter_add(CounterKind::DappCollected, id.clone(), 1);
+        }
+        Ok(self.dapp_collected.get(&id).unwrap_or_default())
+    }
+    /// Ordinary users call, cancel the collection and return the collected count
+    pub fn dapp_uncollect(&mut self, id: DappParsedId, caller: &Principal) -> Result<u64, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
 
-        #[allow(clippy::panic)] // ? SAFETY
-        if let Some(a) = self.apis.get(key) {
-            if a.content != api.content {
-                panic!("api already exists");
+        let key = UserCollectionKey::new(*caller, &id);
+        let replica = self.inner_replica_record::<_, u64>(MEMORY_ID_DAPP_COLLECTIONS, &key, None);
+        if self.dapp_collections.remove(&key).is_some() {
+            self.inner_replicate(replica);
+            if let Some(old) = self.dapp_collected.get(&id) {
+                let item = id.to_bytes().to_vec();
+                let new = old.saturating_sub(1);
+                self.dapp_collected.insert(id.clone(), new);
+                self.inner_counter_changed(CounterKind::DappCollected, item, old, new);
             }
-            return;
+        }
+        Ok(self.dapp_collected.get(&id).unwrap_or_default())
+    }
+    /// Ordinary users call, the dapps collected by the caller
+    pub fn my_collections(&self, caller: &Principal, offset: u64, limit: u64) -> Vec<String> {
+        self.dapp_collections
+            .range(UserCollectionKey::first(*caller)..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.user == *caller)
+            .filter_map(|key| self.dapp.get(&key.dapp()))
+            .map(|dapp| dapp.id.as_ref().to_string())
+            .skip(offset as usize)
+            .take(limit.min(MAX_COLLECTIONS_LIMIT) as usize)
+            .collect()
+    }
+    /// Estimated unique callers in the last days, today included
+    pub fn dapp_unique_users(&self, id: DappParsedId, days: u32) -> Result<u64, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+
+        if !self.dapp.contains_key(&id) {
+            return Err(format!("dapp is missing: {}", id.0.as_ref()));
         }
 
-        self.apis.insert(key.to_owned(), api);
+        let today = today();
+        let days = days.clamp(1, UNIQUE_USERS_KEEP_DAYS);
+        let mut unique = UniqueUsers::default();
+        for (_, sketch) in self
+            .dapp_unique
+            .range(DappDayKey(id.clone(), (today + 1).saturating_sub(days))..=DappDayKey(id, today))
+        {
+            unique.merge(&sketch);
+        }
+        Ok(unique.estimate())
     }
-    pub fn apis_query(&self, id: ApiDataParsedId) -> Option<ApiData> {
-        id.check_canister_id(&ic_cdk::id()).ok()?;
-        let key = &id.hash; // key
 
-        self.apis.get(key)
+    // ================== canister consumers ==================
+
+    pub fn trusted_canisters(&self) -> Vec<Principal> {
+        self.settings.get().trusted_canisters.clone()
+    }
+    pub fn is_trusted_canister(&self, caller: &Principal) -> bool {
+        self.settings.get().trusted_canisters.contains(caller)
+    }
+    // ! Administrator modification
+    pub fn trusted_canisters_update(&mut self, canisters: Vec<Principal>) {
+        let mut settings = self.settings.get().to_owned();
+        settings.trusted_canisters = canisters;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        self.settings.set(settings).unwrap();
     }
+    /// Other canisters call, the access must be checked before unless the caller is trusted
+    pub fn dapp_get_for_canister(&self, id: DappParsedId) -> Result<DappRecord, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
 
-    // ================== combined ==================
+        let dapp = self.inner_dapp_query(id.clone(), false)?; // Do not increase accessed
+        let publisher = self
+            .inner_dapp_owner(&id)
+            .and_then(|publisher| self.publisher.get(&publisher))
+            .map(|publisher| publisher.anchor.as_ref().to_string());
+        let rules = self.dapp_access_rules.get(&id).unwrap_or_default();
+        let value = serde_json::to_value(&dapp).map_err(|err| format!("serialize failed: {err}"))?;
+        let mut references = ItemReferences::default();
+        value_references(&value, &mut references);
+        Ok(DappRecord {
+            anchor: dapp.id.as_ref().to_string(),
+            publisher,
+            name: value_text(&value, &["info", "name"]),
+            icon: value_text(&value, &["info", "icon"]),
+            description: value_text(&value, &["info", "description"]),
+            category: value_text(&value, &["category"]),
+            balance: rules.balance,
+            issuer: rules.issuer,
+            accessed: dapp.accessed,
+            called: dapp.called,
+            collected: dapp.collected,
+            references,
+        })
+    }
+    /// Other canisters call
+    pub fn combined_get_for_canister(&self, id: CombinedParsedId) -> Result<CombinedRecord, String> {
+        id.check_canister_id(&self.canister_id())?;
+        let combined = self
+            .inner_combined_query(id.hash.to_owned())
+            .ok_or_else(|| "combined is missing".to_string())?;
+        Ok(CombinedRecord {
+            anchor: combined.anchor.as_ref().to_string(),
+            called: combined.called,
+            references: item_references(&combined)?,
+        })
+    }
 
-    fn inner_combined_increment_called(&mut self, key: CombinedHash) -> Result<(), String> {
-        if let Some(called) = self.combined_called.get(&key) {
-            self.combined_called.insert(key, called + 1);
+    // ================== batch ==================
+
+    /// The uploaded item of the anchor.
+    /// The access of the dapp is checked without the balance requirements, which are checked by the caller
+    pub fn anchor_item(
+        &self,
+        anchor: &str,
+        verified: Option<DappVerified>,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+    ) -> Result<AnchorItem, String> {
+        let kind = anchor_kind(anchor)?;
+        let json = match kind {
+            EntityKind::Publisher => self.publisher_query(anchor.try_into()?).map(|item| to_json(&item)),
+            EntityKind::Code => self.code_query(anchor.try_into()?).map(|item| to_json(&item)),
+            EntityKind::Api => self.apis_query(anchor.try_into()?).map(|item| to_json(&item)),
+            EntityKind::Combined => self.combined_query(anchor.try_into()?).map(|item| to_json(&item)),
+            EntityKind::Dapp => Some(to_json(&self.dapp_query_by_token(
+                anchor.try_into()?,
+                verified,
+                token,
+                caller,
+            )?)),
         }
-        Ok(())
+        .ok_or_else(|| format!("anchor is missing: {anchor}"))??;
+        Ok(AnchorItem {
+            anchor: anchor.to_string(),
+            kind,
+            json,
+        })
     }
-    fn inner_combined_query(&self, key: CombinedHash) -> Option<Combined> {
-        if let Some(mut combined) = self.combined.get(&key) {
-            combined.called = self.combined_called.get(&key).unwrap_or_default();
-            return Some(combined);
+    /// The item of the anchor tagged by its kind.
+    /// The access of the dapp is checked without the balance requirements, which are checked by the caller
+    pub fn query_anchor(
+        &self,
+        anchor: &str,
+        verified: Option<DappVerified>,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+    ) -> Result<AnchorValue, String> {
+        let item = self.anchor_item(anchor, verified, token, caller)?;
+        Ok(match item.kind {
+            EntityKind::Publisher => AnchorValue::Publisher(item.json),
+            EntityKind::Code => AnchorValue::Code(item.json),
+            EntityKind::Api => AnchorValue::Api(item.json),
+            EntityKind::Combined => AnchorValue::Combined(item.json),
+            EntityKind::Dapp => AnchorValue::Dapp(item.json),
+        })
+    }
+    /// The dapp and every item it depends on, the publisher included.
+    /// The items are paged by count and size in the order of the traversal, the cursor holds the rest of it
+    pub fn resolve_dapp(
+        &self,
+        anchor: &str,
+        verified: Option<DappVerified>,
+        token: Option<&SignedToken>,
+        caller: &Principal,
+        cursor: Option<ResolveCursor>,
+    ) -> Result<ResolvedDapp, String> {
+        let id: DappParsedId = anchor.try_into()?;
+        let key: WrappedDappId = (&id).into();
+        let dapp = self.dapp_query_by_token(id, verified, token, caller)?; // Checked on every page
+
+        let mut items = vec![];
+        let (mut queue, mut seen): (VecDeque<String>, HashSet<String>) = match cursor {
+            Some(cursor) => (cursor.queue.into(), cursor.seen.into_iter().collect()),
+            None => {
+                let owner = self
+                    .inner_dapp_owner(&key)
+                    .and_then(|publisher| self.publisher.get(&publisher))
+                    .map(|publisher| publisher.anchor.as_ref().to_string());
+                let json = to_json(&dapp)?;
+                let mut queue: VecDeque<String> = owner.into_iter().collect();
+                queue.extend(referenced_anchors(&json));
+                items.push(AnchorItem {
+                    anchor: anchor.to_string(),
+                    kind: EntityKind::Dapp,
+                    json,
+                });
+                (queue, HashSet::from([anchor.to_string()]))
+            }
+        };
+
+        let mut bytes: usize = items.iter().map(|item| item.json.len()).sum();
+        let mut missing = vec![];
+        while let Some(anchor) = queue.front() {
+            if MAX_RESOLVE_ITEMS <= items.len() {
+                break;
+            }
+            if seen.contains(anchor) {
+                queue.pop_front();
+                continue;
+            }
+            match self.anchor_item(anchor, None, None, caller) {
+                Ok(item) => {
+                    if !items.is_empty() && MAX_RESOLVE_BYTES < bytes + item.json.len() {
+                        break; // The item is the first of the next page
+                    }
+                    bytes += item.json.len();
+                    queue.pop_front();
+                    seen.insert(item.anchor.clone());
+                    queue.extend(referenced_anchors(&item.json));
+                    items.push(item);
+                }
+                Err(_) => {
+                    if let Some(anchor) = queue.pop_front() {
+                        seen.insert(anchor.clone());
+                        missing.push(anchor);
+                    }
+                }
+            }
         }
-        None
+
+        let next = (!queue.is_empty()).then(|| ResolveCursor {
+            queue: queue.into(),
+            seen: seen.into_iter().collect(),
+        });
+        Ok(ResolvedDapp { items, missing, next })
     }
 
-    // ! Administrator insert
-    pub fn combined_update(&mut self, combined: Combined) {
+    // ================== changes ==================
+
+    fn inner_change(&mut self, kind: EntityKind, anchor: String, action: ChangeAction) {
+        let seq = *self.changes_seq.get();
+        let event = ChangeEvent {
+            seq,
+            kind,
+            anchor,
+            action,
+            timestamp: to_mills(ic_cdk::api::time()),
+        };
+        self.changes.insert(seq, event);
         #[allow(clippy::unwrap_used)] // ? SAFETY
-        let id: CombinedParsedId = combined.anchor.as_ref().as_str().try_into().unwrap();
+        self.changes_seq.set(seq + 1).unwrap();
+        // The indexers too far behind must scan the anchors again
+        if MAX_CHANGES < self.changes.len() {
+            if let Some((first, _)) = self.changes.first_key_value() {
+                self.changes.remove(&first);
+            }
+        }
+    }
+    /// The items are replaced by the wipe or the import, the indexers must scan the anchors again.
+    /// One sequence is skipped, so that first is beyond the next of every indexer
+    fn inner_changes_reset(&mut self) {
+        self.changes.clear_new();
+        self.changes_pending.clear_new();
+        let seq = *self.changes_seq.get();
         #[allow(clippy::unwrap_used)] // ? SAFETY
-        id.check_canister_id(&ic_cdk::id()).unwrap();
-        let key = &id.hash; // key
+        self.changes_seq.set(seq + 1).unwrap();
+    }
+    /// The counters change too often, they are flushed by the timer as one event per item
+    fn inner_change_counter(&mut self, kind: CounterKind, item: &[u8]) {
+        let kind = match kind {
+            CounterKind::CombinedCalled => EntityKind::Combined,
+            _ => EntityKind::Dapp,
+        };
+        self.changes_pending.insert(OwnedKey::new(kind, item.to_vec()), ());
+    }
+    /// Called by the timer, return the count of the flushed items
+    pub fn changes_flush(&mut self) -> u64 {
+        let pending: Vec<OwnedKey> = self
+            .changes_pending
+            .iter()
+            .map(|(key, _)| key)
+            .take(MAX_CHANGES_FLUSH)
+            .collect();
+        let mut flushed = 0;
+        for key in pending {
+            self.changes_pending.remove(&key);
+            let (kind, anchor) = if key.is(EntityKind::Combined) {
+                let anchor = self
+                    .combined
+                    .get(&CombinedHash::from_bytes(Cow::Borrowed(key.item())))
+                    .map(|combined| combined.anchor.as_ref().to_string());
+                (EntityKind::Combined, anchor)
+            } else {
+                let id = WrappedDappId::from_bytes(Cow::Borrowed(key.item()));
+                let anchor = self.dapp.contains_key(&id).then(|| id.0.as_ref().to_string());
+                (EntityKind::Dapp, anchor)
+            };
+            if let Some(anchor) = anchor {
+                self.inner_change(kind, anchor, ChangeAction::CounterFlush);
+                flushed += 1;
+            }
+        }
+        flushed
+    }
+    /// Ordinary users call, the events from the sequence
+    pub fn changes_since(&self, seq: u64, limit: u64) -> ChangesPage {
+        let events: Vec<ChangeEvent> = self
+            .changes
+            .range(seq..)
+            .map(|(_, event)| event)
+            .take(limit.min(MAX_CHANGES_LIMIT) as usize)
+            .collect();
+        ChangesPage {
+            first: self
+                .changes
+                .first_key_value()
+                .map(|(first, _)| first)
+                .unwrap_or_else(|| *self.changes_seq.get()),
+            next: events.last().map_or(seq, |event| event.seq + 1),
+            events,
+        }
+    }
 
-        // The same content is not allowed to be inserted
-        #[allow(clippy::panic)] // ? SAFETY
-        if let Some(o) = self.combined.get(key) {
-            if o.components != combined.components {
-                panic!("combined already exists");
+    // ================== counters ==================
+
+    fn inner_counter_apply(&mut self, anchor: &str, kind: CounterKind, delta: u64) -> Result<u64, String> {
+        if let CounterKind::DappCollected = kind {
+            return Err("collected is derived from the collections of users, recount it by counters_reset".into());
+        }
+        if let CounterKind::CombinedCalled = kind {
+            let id: CombinedParsedId = anchor.try_into()?;
+            id.check_canister_id(&self.canister_id())?;
+            let item = id.hash.to_bytes().to_vec();
+            let (old, new) = counter_add(&mut self.combined_called, id.hash, delta)
+                .ok_or_else(|| format!("combined is missing: {anchor}"))?;
+            self.inner_counter_changed(kind, item, old, new);
+            return Ok(new);
+        }
+        let id: DappParsedId = anchor.try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+        self.inner_dapp_counter_add(kind, id, delta)
+            .ok_or_else(|| format!("dapp is missing: {anchor}"))
+    }
+    fn inner_counter_set(&mut self, anchor: &str, kind: CounterKind, value: u64) -> Result<(), String> {
+        if let CounterKind::DappCollected = kind {
+            return Err("collected is derived from the collections of users, recount it by counters_reset".into());
+        }
+        let (item, old) = if let CounterKind::CombinedCalled = kind {
+            let id: CombinedParsedId = anchor.try_into()?;
+            id.check_canister_id(&self.canister_id())?;
+            if !self.combined.contains_key(&id.hash) {
+                return Err(format!("combined is missing: {anchor}"));
             }
-            return;
+            let item = id.hash.to_bytes().to_vec();
+            (item, self.combined_called.insert(id.hash, value))
+        } else {
+            let id: DappParsedId = anchor.try_into()?;
+            id.check_canister_id(&self.canister_id())?;
+            let id: WrappedDappId = id.into(); // key
+            if !self.dapp.contains_key(&id) {
+                return Err(format!("dapp is missing: {anchor}"));
+            }
+            let item = id.to_bytes().to_vec();
+            let counter = self
+                .inner_dapp_counter(kind)
+                .ok_or_else(|| format!("wrong counter of dapp: {kind:?}"))?;
+            (item, counter.insert(id, value))
+        };
+        let replica = self.inner_replica_counter_set(kind, &item, value);
+        self.inner_change_counter(kind, &item);
+        self.inner_rank_set(kind, item, old, value);
+        self.inner_replicate(replica);
+        Ok(())
+    }
+    /// Keep the leaderboards in step with the counter
+    fn inner_counter_changed(&mut self, kind: CounterKind, item: Vec<u8>, old: u64, new: u64) {
+        let replica = self.inner_replica_counter(kind, &item, old, new);
+        self.inner_change_counter(kind, &item);
+        self.inner_rank_set(kind, item.clone(), Some(old), new);
+        self.inner_rank_period_add(kind, item, new.saturating_sub(old));
+        self.inner_replicate(replica);
+    }
+
+    /// Recount the collected from the collections of users, the legacy value is replaced.
+    /// The collections are ordered by the user, so all of them are scanned
+    fn inner_dapp_collected_recount(&mut self, anchor: &str) -> Result<(), String> {
+        let id: DappParsedId = anchor.try_into()?;
+        id.check_canister_id(&self.canister_id())?;
+        let id: WrappedDappId = id.into(); // key
+        if !self.dapp.contains_key(&id) {
+            return Err(format!("dapp is missing: {anchor}"));
         }
 
-        self.combined_called.insert(key.to_owned(), combined.called);
-        self.combined.insert(key.to_owned(), combined);
+        let value = collections_count(&self.dapp_collections, &id);
+        let item = id.to_bytes().to_vec();
+        let old = self.dapp_collected.insert(id, value);
+        let replica = self.inner_replica_counter_set(CounterKind::DappCollected, &item, value);
+        self.inner_change_counter(CounterKind::DappCollected, &item);
+        self.inner_rank_set(CounterKind::DappCollected, item, old, value);
+        self.inner_replicate(replica);
+        Ok(())
     }
-    pub fn combined_increment_called(&mut self, id: CombinedParsedId) -> Result<(), String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let key = &id.hash; // key
-        self.inner_combined_increment_called(key.to_owned())
+
+    // ! Administrator modification
+    /// The collected of dapps is derived, it is rejected like counters_set
+    pub fn counters_apply_batch(&mut self, items: Vec<(String, CounterKind, u64)>) -> Vec<Result<u64, String>> {
+        items
+            .into_iter()
+            .map(|(anchor, kind, delta)| self.inner_counter_apply(&anchor, kind, delta))
+            .collect()
     }
-    // ! Administrator call
-    pub fn combined_query(&self, id: CombinedParsedId) -> Option<Combined> {
-        id.check_canister_id(&ic_cdk::id()).ok()?;
-        let key = &id.hash; // key
-        self.inner_combined_query(key.to_owned())
+    // ! Administrator modification
+    /// The collected of dapps is derived from the collections of users, it can only be recounted by counters_reset
+    pub fn counters_set(&mut self, anchor: &str, kind: CounterKind, value: u64) -> Result<(), String> {
+        self.inner_counter_set(anchor, kind, value)
+    }
+    // ! Administrator modification
+    /// The collected of dapps is recounted from the collections of users instead of set to 0
+    pub fn counters_reset(&mut self, anchor: &str, kind: CounterKind) -> Result<(), String> {
+        if let CounterKind::DappCollected = kind {
+            return self.inner_dapp_collected_recount(anchor);
+        }
+        self.inner_counter_set(anchor, kind, 0)
     }
 
-    // ================== dapp ==================
+    // ================== ranks ==================
 
-    // The current jar can only check these two items
-    fn inner_dapp_access_by_timestamp_and_token(
-        &self,
-        key: &WrappedDappId,
-        verified: Option<DappVerified>,
-    ) -> Result<(), String> {
-        if let Some(access) = self.dapp_accesses.get(key) {
-            if !access.access_by_timestamp_and_token(now(), verified.as_ref()) {
-                return Err(format!("access is deny: {}", key.0.as_ref()));
-            }
+    fn inner_rank_set(&mut self, kind: CounterKind, item: Vec<u8>, old: Option<u64>, new: u64) {
+        if let Some(old) = old {
+            self.ranks
+                .remove(&RankKey::new(kind, RankPeriod::Total, 0, old, item.clone()));
         }
-        Ok(())
+        self.ranks
+            .insert(RankKey::new(kind, RankPeriod::Total, 0, new, item), ());
     }
-    fn inner_dapp_increment_accessed(&mut self, key: WrappedDappId) -> Result<(), String> {
-        if let Some(accessed) = self.dapp_accessed.get(&key) {
-            self.dapp_accessed.insert(key.clone(), accessed + 1);
+    fn inner_rank_period_add(&mut self, kind: CounterKind, item: Vec<u8>, delta: u64) {
+        if delta == 0 {
+            return;
+        }
+        for period in [RankPeriod::Day(None), RankPeriod::Week(None)] {
+            let index = rank_period_index(period);
+            let key = PeriodCounterKey::new(kind, period, index, item.clone());
+            let old = self.period_counters.get(&key);
+            let new = old.unwrap_or_default().saturating_add(delta);
+            self.period_counters.insert(key, new);
+            if let Some(old) = old {
+                self.ranks.remove(&RankKey::new(kind, period, index, old, item.clone()));
+            }
+            self.ranks
+                .insert(RankKey::new(kind, period, index, new, item.clone()), ());
         }
-        Ok(())
     }
-    fn inner_dapp_increment_called(&mut self, key: WrappedDappId) -> Result<(), String> {
-        if let Some(called) = self.dapp_called.get(&key) {
-            self.dapp_called.insert(key.clone(), called + 1);
+    fn inner_rank_iter(&self, kind: CounterKind, period: RankPeriod) -> impl Iterator<Item = RankKey> + '_ {
+        let first = RankKey::first(kind, period, rank_period_index(period));
+        self.ranks
+            .range(first.clone()..)
+            .map(|(key, _)| key)
+            .take_while(move |key| key.is_same_board(&first))
+    }
+
+    pub fn top_dapps(
+        &self,
+        metric: CounterKind,
+        period: RankPeriod,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<RankItem>, String> {
+        if let CounterKind::CombinedCalled = metric {
+            return Err("wrong metric of dapp: CombinedCalled".into());
         }
-        Ok(())
+        Ok(self
+            .inner_rank_iter(metric, period)
+            .filter_map(|key| {
+                let dapp = self.dapp.get(&WrappedDappId::from_bytes(Cow::Borrowed(&key.item)))?;
+                if dapp.frozen.is_some() {
+                    return None; // Frozen dapps are not displayed
+                }
+                Some(RankItem {
+                    anchor: dapp.id.as_ref().to_string(),
+                    count: key.count(),
+                })
+            })
+            .skip(offset as usize)
+            .take(limit.min(MAX_RANK_LIMIT) as usize)
+            .collect())
     }
-    #[allow(unused)]
-    fn inner_dapp_query_with_increment_accessed(&mut self, key: WrappedDappId) -> Result<DappView, String> {
-        if let Some(mut dapp) = self.dapp.get(&key) {
-            if dapp.frozen.is_some() {
-                return Err(format!("dapp is frozen: {}", key.0.as_ref()));
+    pub fn top_combined(&self, period: RankPeriod, offset: u64, limit: u64) -> Vec<RankItem> {
+        self.inner_rank_iter(CounterKind::CombinedCalled, period)
+            .filter_map(|key| {
+                let combined = self.combined.get(&CombinedHash::from_bytes(Cow::Borrowed(&key.item)))?;
+                Some(RankItem {
+                    anchor: combined.anchor.as_ref().to_string(),
+                    count: key.count(),
+                })
+            })
+            .skip(offset as usize)
+            .take(limit.min(MAX_RANK_LIMIT) as usize)
+            .collect()
+    }
+    // ! Administrator modification
+    /// Remove the daily and weekly leaderboards before the day, return the number of removed items
+    pub fn rank_prune(&mut self, before_day: u32) -> u64 {
+        let mut removed = 0;
+        for kind in [
+            CounterKind::DappCalled,
+            CounterKind::DappAccessed,
+            CounterKind::DappCollected,
+            CounterKind::CombinedCalled,
+        ] {
+            for (period, before) in [
+                (RankPeriod::Day(None), before_day),
+                (RankPeriod::Week(None), before_day / 7),
+            ] {
+                let keys: Vec<RankKey> = self
+                    .ranks
+                    .range(RankKey::first(kind, period, 0)..RankKey::first(kind, period, before))
+                    .map(|(key, _)| key)
+                    .take(MAX_RANK_PRUNE)
+                    .collect();
+                removed += keys.len() as u64;
+                for key in keys {
+                    self.ranks.remove(&key);
+                }
+                let keys: Vec<PeriodCounterKey> = self
+                    .period_counters
+                    .range(
+                        PeriodCounterKey::new(kind, period, 0, vec![])
+                            ..PeriodCounterKey::new(kind, period, before, vec![]),
+                    )
+                    .map(|(key, _)| key)
+                    .take(MAX_RANK_PRUNE)
+                    .collect();
+                for key in keys {
+                    self.period_counters.remove(&key);
+                }
             }
-            dapp.access = self
-                .dapp_accesses
-                .get(&key)
-                .ok_or_else(|| "dapp access is missing".to_string())?;
-            dapp.accessed = self.dapp_accessed.get(&key).unwrap_or_default();
-            dapp.called = self.dapp_called.get(&key).unwrap_or_default();
-            dapp.collected = self.dapp_collected.get(&key).unwrap_or_default();
-            self.inner_dapp_increment_accessed(key)?;
-            return Ok(dapp.into());
         }
-        Err(format!("dapp is missing: {}", key.0.as_ref()))
+        removed
     }
-    fn inner_dapp_query(&self, key: WrappedDappId, admin: bool) -> Result<Dapp, String> {
-        if let Some(mut dapp) = self.dapp.get(&key) {
-            if !admin && dapp.frozen.is_some() {
-                return Err(format!("Frozen: {}", dapp.reason));
+}
+
+/// Collections of the dapp by all users
+fn collections_count(collections: &StableBTreeMap<UserCollectionKey, u64>, dapp: &WrappedDappId) -> u64 {
+    let dapp = dapp.to_bytes();
+    collections
+        .iter()
+        .filter(|(key, _)| key.dapp().to_bytes() == dapp)
+        .count() as u64
+}
+
+/// Add to the counter of the existing item, return the old and new value
+fn counter_add<K: Storable + Ord + Clone>(
+    counter: &mut StableBTreeMap<K, u64>,
+    key: K,
+    delta: u64,
+) -> Option<(u64, u64)> {
+    let old = counter.get(&key)?;
+    let new = old.saturating_add(delta);
+    counter.insert(key, new);
+    Some((old, new))
+}
+
+type AnchorParser = fn(&str) -> bool;
+
+/// Formats of the anchors, the first matched kind is the kind of the anchor
+const ANCHOR_PARSERS: [(EntityKind, AnchorParser); 5] = [
+    (EntityKind::Publisher, |anchor| {
+        PublisherParsedId::try_from(anchor).is_ok()
+    }),
+    (EntityKind::Code, |anchor| CodeDataParsedId::try_from(anchor).is_ok()),
+    (EntityKind::Api, |anchor| ApiDataParsedId::try_from(anchor).is_ok()),
+    (EntityKind::Combined, |anchor| {
+        CombinedParsedId::try_from(anchor).is_ok()
+    }),
+    (EntityKind::Dapp, |anchor| DappParsedId::try_from(anchor).is_ok()),
+];
+
+/// Kind of the anchor by its format
+pub fn anchor_kind(anchor: &str) -> Result<EntityKind, String> {
+    inner_anchor_kind(anchor, &ANCHOR_PARSERS)
+}
+
+fn inner_anchor_kind(anchor: &str, parsers: &[(EntityKind, AnchorParser)]) -> Result<EntityKind, String> {
+    parsers
+        .iter()
+        .find(|(_, parse)| parse(anchor))
+        .map(|(kind, _)| *kind)
+        .ok_or_else(|| format!("unknown anchor: {anchor}"))
+}
+
+fn to_json<T: Serialize>(item: &T) -> Result<String, String> {
+    serde_json::to_string(item).map_err(|err| format!("serialize failed: {err}"))
+}
+
+/// Anchors in the reference fields of the model, the fields are named the same in every kind
+fn item_references<T: Serialize>(item: &T) -> Result<ItemReferences, String> {
+    let value = serde_json::to_value(item).map_err(|err| format!("serialize failed: {err}"))?;
+    let mut references = ItemReferences::default();
+    value_references(&value, &mut references);
+    Ok(references)
+}
+
+/// The text field of the model by the path of its field names, empty if missing
+fn value_text(value: &serde_json::Value, path: &[&str]) -> String {
+    path.iter()
+        .try_fold(value, |value, key| value.get(key))
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn value_references(value: &serde_json::Value, references: &mut ItemReferences) {
+    match value {
+        serde_json::Value::Array(values) => values.iter().for_each(|value| value_references(value, references)),
+        serde_json::Value::Object(values) => {
+            for (key, value) in values {
+                let anchors = match key.as_str() {
+                    "code_anchors" => &mut references.code_anchors,
+                    "apis_anchors" => &mut references.apis_anchors,
+                    "combined_anchors" => &mut references.combined_anchors,
+                    _ => {
+                        value_references(value, references);
+                        continue;
+                    }
+                };
+                if let serde_json::Value::Array(values) = value {
+                    for anchor in values.iter().filter_map(|value| value.as_str()) {
+                        if !anchors.iter().any(|a| a == anchor) {
+                            anchors.push(anchor.to_string());
+                        }
+                    }
+                }
             }
-            dapp.access = self
-                .dapp_accesses
-                .get(&key)
-                .ok_or_else(|| "dapp access is missing".to_string())?;
-            dapp.accessed = self.dapp_accessed.get(&key).unwrap_or_default();
-            dapp.called = self.dapp_called.get(&key).unwrap_or_default();
-            dapp.collected = self.dapp_collected.get(&key).unwrap_or_default();
-            return Ok(dapp);
         }
-        Err(format!("dapp is missing: {}", key.0.as_ref()))
+        _ => {}
     }
+}
 
-    // ! Administrator insert
-    pub fn dapp_update(&mut self, dapp: Dapp) {
-        #[allow(clippy::unwrap_used)] // ? SAFETY
-        let id: DappParsedId = dapp.id.as_ref().as_str().try_into().unwrap();
-        #[allow(clippy::unwrap_used)] // ? SAFETY
-        id.check_canister_id(&ic_cdk::id()).unwrap();
-        let id: WrappedDappId = id.into(); // key
+/// Anchors in the reference fields of the JSON of the item, the combined ones first
+fn referenced_anchors(json: &str) -> Vec<String> {
+    let mut references = ItemReferences::default();
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(json) {
+        value_references(&value, &mut references);
+    }
+    let ItemReferences {
+        code_anchors,
+        apis_anchors,
+        combined_anchors,
+    } = references;
+    combined_anchors
+        .into_iter()
+        .chain(code_anchors)
+        .chain(apis_anchors)
+        .collect()
+}
 
-        self.dapp_accesses.insert(id.clone(), dapp.access.to_owned());
-        self.dapp_accessed.insert(id.clone(), dapp.accessed);
-        self.dapp_collected.insert(id.clone(), dapp.collected);
-        self.dapp_called.insert(id.clone(), dapp.called);
-        self.dapp.insert(id, dapp);
+fn token_nonce_key(dapp: &WrappedDappId, token: &SignedToken) -> TokenNonceKey {
+    TokenNonceKey::new(token.expires, dapp, token.nonce, token.caller)
+}
+
+/// Remove the nonces expired before now by the range of the expiry, then record the nonce.
+/// The nonces are kept no longer than the ttl of the tokens, so the callers are never refused for the count
+fn token_nonce_use<P: Storable + Clone>(
+    nonces: &mut StableBTreeMap<TokenNonceKey, P>,
+    key: TokenNonceKey,
+    publisher: &P,
+    now: u64,
+) -> Result<(), String> {
+    let expired: Vec<TokenNonceKey> = nonces
+        .range(..TokenNonceKey::first(now))
+        .map(|(key, _)| key)
+        .take(MAX_TOKEN_NONCES_PRUNE)
+        .collect();
+    for key in expired {
+        nonces.remove(&key);
     }
-    // ! Administrator modification
-    pub fn dapp_increment_called_by_admin(&mut self, id: DappParsedId) -> Result<(), String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let id: WrappedDappId = id.into(); // key
 
-        self.inner_dapp_increment_called(id)
+    if nonces.contains_key(&key) {
+        return Err("access is deny: token is used".into());
     }
-    // ! Administrator modification
-    pub fn dapp_update_collected(&mut self, id: DappParsedId, collected: u64) -> Result<(), String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let id: WrappedDappId = id.into(); // key
+    nonces.insert(key, publisher.clone());
+    Ok(())
+}
+
+fn rank_period_index(period: RankPeriod) -> u32 {
+    match period {
+        RankPeriod::Total => 0,
+        RankPeriod::Day(index) => index.unwrap_or_else(today),
+        RankPeriod::Week(index) => index.unwrap_or_else(|| today() / 7),
+    }
+}
+
+impl AdminUsers {
+    fn is_admin(&self, caller: &Principal) -> bool {
+        self.users.contains(caller)
+    }
+}
 
-        if self.dapp_collected.get(&id).is_some() {
-            self.dapp_collected.insert(id, collected);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_add() {
+        let mut counter: StableBTreeMap<u64, u64> = StableBTreeMap::init(get_virtual_memory(250));
+        assert_eq!(counter_add(&mut counter, 1, 5), None); // The missing item is not created
+        assert!(counter.get(&1).is_none());
+
+        counter.insert(1, 3);
+        assert_eq!(counter_add(&mut counter, 1, 5), Some((3, 8)));
+        assert_eq!(counter_add(&mut counter, 1, 0), Some((8, 8)));
+        assert_eq!(counter_add(&mut counter, 1, u64::MAX), Some((8, u64::MAX))); // Saturated
+        assert_eq!(counter.get(&1), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_collections_count() {
+        let mut collections: StableBTreeMap<UserCollectionKey, u64> = StableBTreeMap::init(get_virtual_memory(250));
+        let dapp = |index: u8| WrappedDappId::from_bytes(Cow::Owned(vec![index; 12]));
+        let user = |index: u8| Principal::from_slice(&[index]);
+        for index in 1..=3 {
+            collections.insert(UserCollectionKey::new(user(index), &dapp(1)), 0);
         }
+        collections.insert(UserCollectionKey::new(user(1), &dapp(2)), 0);
 
-        Ok(())
+        assert_eq!(collections_count(&collections, &dapp(1)), 3);
+        assert_eq!(collections_count(&collections, &dapp(2)), 1);
+        assert_eq!(collections_count(&collections, &dapp(3)), 0);
     }
-    // ! Administrator call
-    pub fn dapp_query_by_admin(&self, id: DappParsedId) -> Result<Dapp, String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let id: WrappedDappId = id.into(); // key
-        self.inner_dapp_query(id, true) // Do not increase accessed
+
+    #[test]
+    fn test_token_nonce_use() {
+        let mut nonces: StableBTreeMap<TokenNonceKey, u64> = StableBTreeMap::init(get_virtual_memory(251));
+        let dapp = |index: u8| WrappedDappId::from_bytes(Cow::Owned(vec![index; 12]));
+        let key = |expires: u64, index: u8, nonce: u64| {
+            TokenNonceKey::new(expires, &dapp(index), nonce, Principal::anonymous())
+        };
+
+        assert!(token_nonce_use(&mut nonces, key(100, 1, 1), &1, 10).is_ok());
+        assert!(token_nonce_use(&mut nonces, key(100, 1, 1), &1, 20).is_err()); // Replayed
+        assert!(token_nonce_use(&mut nonces, key(100, 2, 1), &1, 20).is_ok()); // The same nonce of other dapp
+        assert!(token_nonce_use(&mut nonces, key(200, 1, 2), &2, 20).is_ok());
+        assert_eq!(nonces.len(), 3);
+
+        // The expired nonces are removed, the rest are kept until they expire
+        assert!(token_nonce_use(&mut nonces, key(300, 1, 3), &2, 150).is_ok());
+        assert_eq!(nonces.len(), 2);
+        assert!(token_nonce_use(&mut nonces, key(200, 1, 2), &2, 150).is_err());
+
+        // Many tokens of one publisher are not refused
+        for nonce in 10..1010 {
+            assert!(token_nonce_use(&mut nonces, key(400, 1, nonce), &1, 150).is_ok());
+        }
+        assert!(token_nonce_use(&mut nonces, key(500, 1, 1), &1, 450).is_ok());
+        assert_eq!(nonces.len(), 1);
     }
 
-    /// Ordinary user calls, query the permissions required
-    pub fn dapp_query_access(&self, id: DappParsedId) -> Result<DappAccessView, String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let id: WrappedDappId = id.into(); // key
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut source: StableBTreeMap<u64, u64> = StableBTreeMap::init(get_virtual_memory(253));
+        let mut target: StableBTreeMap<u64, u64> = StableBTreeMap::init(get_virtual_memory(254));
+        for i in 0..100 {
+            source.insert(i, i * 7);
+        }
 
-        let access = self
-            .dapp_accesses
-            .get(&id)
-            .ok_or_else(|| "dapp access is missing".to_string())?;
-        Ok(access.into())
+        // Pages of 10 records, chained like state_export
+        let mut after = None;
+        let mut pages = vec![];
+        let mut checksum = vec![];
+        loop {
+            let mut budget = 10 * 16;
+            let mut records = vec![];
+            let done = source.export(253, &mut after, &mut budget, &mut records);
+            let mut data = vec![];
+            ciborium::ser::into_writer(&records, &mut data).unwrap();
+            checksum = snapshot_checksum(&checksum, &data);
+            pages.push((data, checksum.clone()));
+            if done {
+                break;
+            }
+        }
+        assert_eq!(pages.len(), 10);
+
+        // Checked like state_import
+        let mut last = vec![];
+        for (data, checksum) in pages {
+            assert_eq!(snapshot_checksum(&last, &data), checksum);
+            let records: Vec<SnapshotRecord> = ciborium::de::from_reader(&data[..]).unwrap();
+            for record in records {
+                assert_eq!(record.map, 253);
+                target.import(&record.key, &record.value).unwrap();
+            }
+            last = checksum;
+        }
+        assert_eq!(target.len(), 100);
+        assert!(source.iter().eq(target.iter()));
+
+        target.wipe();
+        assert_eq!(target.len(), 0);
     }
-    /// Ordinary users call, pay attention to only the permissions verification of Duration and Token
-    pub fn dapp_increment_called_by_token(
-        &mut self,
-        id: DappParsedId,
-        verified: Option<DappVerified>,
-    ) -> Result<(), String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let id: WrappedDappId = id.into(); // key
 
-        // ! Check the access permissions
-        self.inner_dapp_access_by_timestamp_and_token(&id, verified)?;
+    #[test]
+    fn test_replication_batches() {
+        let mut state = State::default();
+        let secondary = Principal::from_slice(&[1]);
+        state.replication_update(Replication {
+            primary: None,
+            secondaries: vec![secondary],
+        });
+
+        // 3 ops are in one batch
+        let json = "x".repeat(MAX_REPLICATION_BATCH_BYTES / 4);
+        for _ in 0..5 {
+            let op = state.inner_replica_upload(EntityKind::Code, &json);
+            state.inner_replicate(op);
+        }
+        let batches = state.replication_batches();
+        assert_eq!(batches.len(), 1);
+        assert_eq!((batches[0].0, batches[0].1, batches[0].2.len()), (secondary, 0, 3));
+
+        // The pushed ops are removed
+        state.replication_pushed(secondary, 3, Ok(3));
+        assert_eq!(state.replication_log.len(), 2);
+        let batches = state.replication_batches();
+        assert_eq!((batches[0].1, batches[0].2.len()), (3, 2));
+
+        // The secondary stopped at the failed op
+        state.replication_pushed(secondary, 5, Ok(4));
+        let status = state.replicas.get(&secondary).unwrap();
+        assert_eq!((status.next, status.failures), (4, 1));
+        assert!(status.error.is_some());
+        assert_eq!(state.replication_log.len(), 1);
+
+        // At least one op in a batch
+        let op = state.inner_replica_upload(EntityKind::Code, &"x".repeat(MAX_REPLICATION_BATCH_BYTES));
+        state.inner_replicate(op);
+        let batches = state.replication_batches();
+        assert_eq!((batches[0].1, batches[0].2.len()), (4, 1));
+        state.replication_pushed(secondary, 5, Ok(5));
+        let batches = state.replication_batches();
+        assert_eq!((batches[0].1, batches[0].2.len()), (5, 1));
+        state.replication_pushed(secondary, 6, Ok(6));
+        assert!(state.replication_batches().is_empty());
+        assert_eq!(state.replication_log.len(), 0);
+    }
 
-        self.inner_dapp_increment_called(id)
+    #[test]
+    fn test_replicate_apply() {
+        let mut state = State::default();
+        let primary = Principal::from_slice(&[1]);
+        state.replication_update(Replication {
+            primary: Some(primary),
+            secondaries: vec![],
+        });
+        let rank = |count: u64| ReplicaOp::Record {
+            map: MEMORY_ID_RANKS,
+            key: RankKey::new(CounterKind::DappCalled, RankPeriod::Total, 0, count, vec![1])
+                .to_bytes()
+                .to_vec(),
+            value: Some(vec![]),
+        };
+        let admin = ReplicaOp::Record {
+            map: MEMORY_ID_ADMIN,
+            key: vec![],
+            value: Some(vec![]),
+        };
+
+        assert!(state
+            .replicate_apply(&Principal::anonymous(), 0, vec![rank(1)])
+            .is_err());
+
+        // Stop at the failed op
+        assert_eq!(state.replicate_apply(&primary, 0, vec![rank(1), admin, rank(2)]), Ok(1));
+        assert_eq!(state.ranks.len(), 1);
+        let status = state.replicas.get(&primary).unwrap();
+        assert_eq!((status.next, status.failures), (1, 1));
+
+        // The applied ops are skipped
+        assert_eq!(
+            state.replicate_apply(&primary, 0, vec![rank(1), rank(2), rank(3)]),
+            Ok(3)
+        );
+        assert_eq!(state.ranks.len(), 3);
+        let status = state.replicas.get(&primary).unwrap();
+        assert_eq!((status.failures, status.error), (0, None));
+
+        // Some ops are missing
+        assert_eq!(state.replicate_apply(&primary, 5, vec![rank(5)]), Ok(3));
+
+        // Removed by the record without the value
+        let remove = ReplicaOp::Record {
+            map: MEMORY_ID_RANKS,
+            key: RankKey::new(CounterKind::DappCalled, RankPeriod::Total, 0, 3, vec![1])
+                .to_bytes()
+                .to_vec(),
+            value: None,
+        };
+        assert_eq!(state.replicate_apply(&primary, 3, vec![remove]), Ok(4));
+        assert_eq!(state.ranks.len(), 2);
     }
-    /// Ordinary users call, pay attention to only the permissions verification of Duration and Token
-    pub fn dapp_query_by_token(&self, id: DappParsedId, verified: Option<DappVerified>) -> Result<DappView, String> {
-        id.check_canister_id(&ic_cdk::id())?;
-        let id: WrappedDappId = id.into(); // key
 
-        // ! Check the access permissions
-        self.inner_dapp_access_by_timestamp_and_token(&id, verified)?;
+    #[test]
+    fn test_changes_since() {
+        let mut state = State::default();
+        for seq in 5..15 {
+            let event = ChangeEvent {
+                seq,
+                kind: EntityKind::Dapp,
+                anchor: format!("dapp-{seq}"),
+                action: ChangeAction::Insert,
+                timestamp: 0.into(),
+            };
+            state.changes.insert(seq, event); // The events before 5 are pruned
+        }
+        state.changes_seq.set(15).unwrap();
+
+        let page = state.changes_since(0, 4);
+        let seqs: Vec<u64> = page.events.iter().map(|event| event.seq).collect();
+        assert_eq!((seqs, page.first, page.next), (vec![5, 6, 7, 8], 5, 9));
+        let page = state.changes_since(page.next, 100);
+        let seqs: Vec<u64> = page.events.iter().map(|event| event.seq).collect();
+        assert_eq!((seqs, page.first, page.next), (vec![9, 10, 11, 12, 13, 14], 5, 15));
+
+        // Nothing new, the indexer stays at next
+        let page = state.changes_since(15, 100);
+        assert_eq!((page.events.len(), page.first, page.next), (0, 5, 15));
+        let page = state.changes_since(7, 0);
+        assert_eq!((page.events.len(), page.next), (0, 7));
+
+        // The reset moves first beyond the next of every indexer
+        state.inner_changes_reset();
+        let page = state.changes_since(15, 100);
+        assert_eq!((page.events.len(), page.first, page.next), (0, 16, 15));
+    }
 
-        self.inner_dapp_query(id, false).map(|dapp| dapp.into()) // Do not increase accessed
+    #[test]
+    fn test_referenced_anchors() {
+        let json = r#"{
+            "id": "dapp-anchor",
+            "name": "code_anchors",
+            "metadata": {
+                "code_anchors": ["code-1", "code-2"],
+                "apis_anchors": ["api-1"],
+                "combined_anchors": ["combined-1"]
+            },
+            "components": [
+                { "anchor": "not-a-reference", "metadata": { "code_anchors": ["code-2", "code-3"] } }
+            ]
+        }"#;
+        // Only the reference fields are followed, the combined ones first and every anchor once
+        assert_eq!(
+            referenced_anchors(json),
+            vec!["combined-1", "code-1", "code-2", "code-3", "api-1"]
+        );
+        assert!(referenced_anchors(r#"{"anchor": "code-1", "values": ["api-1"]}"#).is_empty());
+        assert!(referenced_anchors("not json").is_empty());
     }
-}
 
-impl AdminUsers {
-    fn is_admin(&self, caller: &Principal) -> bool {
-        self.users.contains(caller)
+    #[test]
+    fn test_value_text() {
+        let value = serde_json::json!({
+            "info": { "name": "dapp", "icon": null },
+            "category": "Tools"
+        });
+        assert_eq!(value_text(&value, &["info", "name"]), "dapp");
+        assert_eq!(value_text(&value, &["category"]), "Tools");
+        // The missing or not text fields are empty
+        assert_eq!(value_text(&value, &["info", "icon"]), "");
+        assert_eq!(value_text(&value, &["info", "description"]), "");
+        assert_eq!(value_text(&value, &["info"]), "");
+    }
+
+    #[test]
+    fn test_anchor_kind() {
+        assert!(anchor_kind("").is_err());
+        assert!(anchor_kind("#").is_err());
+        assert!(anchor_kind("unknown anchor").is_err());
+        assert_eq!(anchor_kind("x").unwrap_err(), "unknown anchor: x");
+
+        // Every kind is parsed once, the publisher first
+        let kinds: Vec<EntityKind> = ANCHOR_PARSERS.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EntityKind::Publisher,
+                EntityKind::Code,
+                EntityKind::Api,
+                EntityKind::Combined,
+                EntityKind::Dapp
+            ]
+        );
+
+        // The anchors matched by the formats
+        let parsers: [(EntityKind, AnchorParser); 5] = [
+            (EntityKind::Publisher, |anchor| anchor.starts_with("#publisher#")),
+            (EntityKind::Code, |anchor| anchor.starts_with("#code#")),
+            (EntityKind::Api, |anchor| anchor.starts_with("#api#")),
+            (EntityKind::Combined, |anchor| anchor.starts_with("#combined#")),
+            (EntityKind::Dapp, |anchor| anchor.starts_with('#')),
+        ];
+        assert_eq!(
+            inner_anchor_kind("#publisher#1", &parsers).unwrap(),
+            EntityKind::Publisher
+        );
+        assert_eq!(inner_anchor_kind("#code#1", &parsers).unwrap(), EntityKind::Code);
+        assert_eq!(inner_anchor_kind("#api#1", &parsers).unwrap(), EntityKind::Api);
+        assert_eq!(
+            inner_anchor_kind("#combined#1", &parsers).unwrap(),
+            EntityKind::Combined
+        );
+        assert_eq!(inner_anchor_kind("#1", &parsers).unwrap(), EntityKind::Dapp);
+        assert_eq!(inner_anchor_kind("1", &parsers).unwrap_err(), "unknown anchor: 1");
     }
 }
@@ -5,6 +5,8 @@ fn print_candid() {
     use std::io::Write;
 
     use candid::Principal;
+
+    use crate::types::*;
     candid::export_service!();
 
     let filename = "storage.did";
@@ -14,3 +16,40 @@ fn print_candid() {
         .write_all(__export_service().as_bytes())
         .unwrap();
 }
+
+#[test]
+fn test_admin_methods() {
+    use std::collections::BTreeSet;
+
+    use crate::apis::ADMIN_METHODS;
+
+    let guard = "guard = \"must_be_admin\")]";
+    let sources = [
+        include_str!("apis.rs"),
+        #[cfg(feature = "index")]
+        include_str!("index.rs"),
+    ];
+    let mut guarded = BTreeSet::new();
+    for source in sources {
+        let mut lines = source.lines();
+        while let Some(line) = lines.next() {
+            if !line.starts_with("#[ic_cdk::") || !line.ends_with(guard) {
+                continue;
+            }
+            let next = lines.next().unwrap();
+            let name = next.split("fn ").nth(1).unwrap().split('(').next().unwrap();
+            guarded.insert(name);
+        }
+    }
+    let listed: BTreeSet<&str> = ADMIN_METHODS.iter().copied().collect();
+    assert_eq!(guarded, listed);
+
+    let did = include_str!("../storage.did");
+    // The index methods are not in the interface of the storage canister
+    for method in ADMIN_METHODS.iter().filter(|method| !method.starts_with("index_")) {
+        assert!(
+            did.contains(&format!("  {method} : (")),
+            "{method} is not in storage.did"
+        );
+    }
+}
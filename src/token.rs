@@ -0,0 +1,153 @@
+use crate::types::*;
+
+/// Domain of the signed message, the token can not be reused by other protocols
+const TOKEN_DOMAIN: &[u8] = b"easydapp-access-token";
+
+/// The message signed by the publisher:
+/// domain | len(dapp) u32 | dapp | len(caller) u8 | caller | expires u64 | nonce u64, integers are big endian
+pub fn token_message(token: &SignedToken) -> Vec<u8> {
+    let caller = token.caller.as_slice();
+    let mut message = Vec::with_capacity(TOKEN_DOMAIN.len() + 4 + token.dapp.len() + 1 + caller.len() + 16);
+    message.extend_from_slice(TOKEN_DOMAIN);
+    message.extend_from_slice(&(token.dapp.len() as u32).to_be_bytes());
+    message.extend_from_slice(token.dapp.as_bytes());
+    message.push(caller.len() as u8);
+    message.extend_from_slice(caller);
+    message.extend_from_slice(&token.expires.to_be_bytes());
+    message.extend_from_slice(&token.nonce.to_be_bytes());
+    message
+}
+
+/// Check the token is not expired and does not live longer than the max ttl
+pub fn check_token_expires(token: &SignedToken, now: u64, max_ttl: u64) -> Result<(), String> {
+    if token.expires <= now {
+        return Err("token is expired".into());
+    }
+    if now.saturating_add(max_ttl) < token.expires {
+        return Err("token lives too long".into());
+    }
+    Ok(())
+}
+
+/// Check the public key can be parsed
+pub fn check_public_key(key: &PublisherKey) -> Result<(), String> {
+    match key.scheme {
+        KeyScheme::Ed25519 => ed25519_key(&key.key).map(|_| ()),
+        KeyScheme::Secp256k1 => secp256k1_key(&key.key).map(|_| ()),
+    }
+}
+
+/// Verify the signature of the token, the expiry and the nonce are checked by the caller
+pub fn verify_signed_token(key: &PublisherKey, token: &SignedToken) -> Result<(), String> {
+    let message = token_message(token);
+    match key.scheme {
+        KeyScheme::Ed25519 => {
+            let signature = ed25519_dalek::Signature::from_slice(&token.signature)
+                .map_err(|err| format!("wrong signature: {err}"))?;
+            ed25519_key(&key.key)?
+                .verify_strict(&message, &signature)
+                .map_err(|_| "signature verification failed".to_string())
+        }
+        KeyScheme::Secp256k1 => {
+            use k256::ecdsa::signature::Verifier;
+            let signature = k256::ecdsa::Signature::from_slice(&token.signature)
+                .map_err(|err| format!("wrong signature: {err}"))?;
+            secp256k1_key(&key.key)?
+                .verify(&message, &signature) // sha256 of the message
+                .map_err(|_| "signature verification failed".to_string())
+        }
+    }
+}
+
+fn ed25519_key(key: &[u8]) -> Result<ed25519_dalek::VerifyingKey, String> {
+    let key: &[u8; 32] = key
+        .try_into()
+        .map_err(|_| "wrong ed25519 public key: length must be 32".to_string())?;
+    ed25519_dalek::VerifyingKey::from_bytes(key).map_err(|err| format!("wrong ed25519 public key: {err}"))
+}
+
+fn secp256k1_key(key: &[u8]) -> Result<k256::ecdsa::VerifyingKey, String> {
+    k256::ecdsa::VerifyingKey::from_sec1_bytes(key).map_err(|err| format!("wrong secp256k1 public key: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(expires: u64) -> SignedToken {
+        SignedToken {
+            dapp: "dapp".into(),
+            caller: Principal::from_slice(&[1, 2, 3]),
+            expires,
+            nonce: 7,
+            signature: vec![],
+        }
+    }
+
+    fn sign_ed25519(seed: u8, token: &mut SignedToken) -> PublisherKey {
+        use ed25519_dalek::Signer;
+        let signing = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]);
+        token.signature = signing.sign(&token_message(token)).to_bytes().to_vec();
+        PublisherKey {
+            scheme: KeyScheme::Ed25519,
+            key: signing.verifying_key().to_bytes().to_vec(),
+        }
+    }
+
+    fn sign_secp256k1(seed: u8, token: &mut SignedToken) -> PublisherKey {
+        use k256::ecdsa::signature::Signer;
+        #[allow(clippy::unwrap_used)] // ? SAFETY
+        let signing = k256::ecdsa::SigningKey::from_bytes(&[seed; 32].into()).unwrap();
+        let signature: k256::ecdsa::Signature = signing.sign(&token_message(token));
+        token.signature = signature.to_bytes().to_vec();
+        PublisherKey {
+            scheme: KeyScheme::Secp256k1,
+            key: signing.verifying_key().to_encoded_point(true).as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_token_message() {
+        let mut expected = TOKEN_DOMAIN.to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 4]);
+        expected.extend_from_slice(b"dapp");
+        expected.extend_from_slice(&[3, 1, 2, 3]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 9]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(token_message(&token(9)), expected);
+    }
+
+    #[test]
+    fn test_verify_signed_token() {
+        type Sign = fn(u8, &mut SignedToken) -> PublisherKey;
+        for sign in [sign_ed25519 as Sign, sign_secp256k1 as Sign] {
+            let mut valid = token(9);
+            let key = sign(1, &mut valid);
+            assert!(check_public_key(&key).is_ok());
+            assert!(verify_signed_token(&key, &valid).is_ok());
+
+            // Signed by other key
+            let mut other = token(9);
+            let other_key = sign(2, &mut other);
+            assert!(verify_signed_token(&other_key, &valid).is_err());
+            assert!(verify_signed_token(&key, &other).is_err());
+
+            // Changed after signed
+            let mut changed = valid.clone();
+            changed.nonce += 1;
+            assert!(verify_signed_token(&key, &changed).is_err());
+            let mut changed = valid.clone();
+            changed.caller = Principal::anonymous();
+            assert!(verify_signed_token(&key, &changed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_token_expires() {
+        assert!(check_token_expires(&token(100), 50, 60).is_ok());
+        assert!(check_token_expires(&token(100), 40, 60).is_ok());
+        assert!(check_token_expires(&token(100), 100, 60).is_err()); // Expired
+        assert!(check_token_expires(&token(100), 150, 60).is_err());
+        assert!(check_token_expires(&token(100), 39, 60).is_err()); // Lives too long
+    }
+}
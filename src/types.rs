@@ -1,5 +1,7 @@
+use candid::CandidType;
 use jelly_model::store::dapp::anchor::DappId;
 use jelly_model::store::dapp::anchor::DappParsedId;
+use jelly_model::types::TimestampMills;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashSet;
@@ -22,6 +24,548 @@ pub struct AdminUsers {
     pub users: HashSet<Principal>,
 }
 
+/// Settings of the canister, changed by administrators
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Settings {
+    #[serde(default)]
+    pub quota: QuotaLimit, // Default quota of publishers
+    #[serde(default)]
+    pub payment: PaymentConfig, // Pricing of the uploads by publishers
+    #[serde(default)]
+    pub limits: UploadLimits, // Uploads are rejected if any limit is crossed
+    #[serde(default)]
+    pub max_payload_bytes: Option<u64>, // Checked before the ingress message is accepted
+    #[serde(default)]
+    pub replication: Replication,
+    #[serde(default)]
+    pub trusted_canisters: Vec<Principal>, // Bypass the access checks of the queries for canisters
+    #[serde(default)]
+    pub origin: Option<Principal>, // The anchors are issued by this canister, set by the import from it
+}
+
+/// The primary pushes the changes to the secondaries.
+/// The secondary serves the anchors issued for the primary
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct Replication {
+    pub primary: Option<Principal>, // This canister is a secondary if set
+    pub secondaries: Vec<Principal>,
+}
+
+/// Change pushed to the secondaries
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum ReplicaOp {
+    Upload {
+        kind: EntityKind,
+        json: String,
+        #[serde(default)]
+        owner: Option<Vec<u8>>, // Storable key of the publisher who owns the item
+    },
+    Counter {
+        kind: CounterKind,
+        item: Vec<u8>,
+        delta: i64,
+    }, // The item is the Storable key of the counter
+    CounterSet {
+        kind: CounterKind,
+        item: Vec<u8>,
+        value: u64,
+    }, // Set by the administrators, the periods are not changed
+    Record {
+        map: u8,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    }, // Storable record of the map like the snapshot, removed if the value is missing
+}
+
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub next: u64,     // The next op to push, or the next op expected from the primary
+    pub failures: u64, // Continuous failures of the pushes on the primary, of the op on the secondary
+    pub error: Option<String>,
+}
+
+/// Thresholds of the circuit breaker, nothing is checked if not set
+#[derive(Debug, Clone, Copy, Default, CandidType, Serialize, Deserialize)]
+pub struct UploadLimits {
+    pub min_cycles: Option<u128>,      // Keep the canister away from the freezing threshold
+    pub max_stable_bytes: Option<u64>, // Size of the stable memory
+}
+
+/// Current state of the canister and the tripped limits
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct HealthView {
+    pub cycles: u128,
+    pub stable_bytes: u64,
+    pub limits: UploadLimits,
+    pub cycles_low: bool,
+    pub storage_full: bool,
+    pub importing: bool,
+    pub exporting: bool,
+    pub uploads_allowed: bool,
+}
+
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize)]
+pub struct QuotaLimit {
+    pub max_bytes: u64,
+    pub max_items: u64,
+}
+
+impl Default for QuotaLimit {
+    fn default() -> Self {
+        Self {
+            max_bytes: 100 * 1024 * 1024, // 100 MiB
+            max_items: 10_000,
+        }
+    }
+}
+
+impl Storable for QuotaLimit {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = [0_u8; 16];
+        bytes[..8].copy_from_slice(&self.max_bytes.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.max_items.to_be_bytes());
+        Cow::Owned(bytes.to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (max_bytes, max_items) = u64_pair_from_bytes(&bytes);
+        Self { max_bytes, max_items }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
+
+/// Storage used by the publisher
+#[derive(Debug, Clone, Copy, Default, CandidType, Serialize, Deserialize)]
+pub struct PublisherUsage {
+    pub bytes: u64,
+    pub items: u64,
+}
+
+impl Storable for PublisherUsage {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = [0_u8; 16];
+        bytes[..8].copy_from_slice(&self.bytes.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.items.to_be_bytes());
+        Cow::Owned(bytes.to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (bytes, items) = u64_pair_from_bytes(&bytes);
+        Self { bytes, items }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
+
+fn u64_pair_from_bytes(bytes: &[u8]) -> (u64, u64) {
+    let mut first = [0_u8; 8];
+    first.copy_from_slice(&bytes[..8]);
+    let mut second = [0_u8; 8];
+    second.copy_from_slice(&bytes[8..16]);
+    (u64::from_be_bytes(first), u64::from_be_bytes(second))
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct PublisherUsageView {
+    pub usage: PublisherUsage,
+    pub quota: QuotaLimit,
+}
+
+/// Usage of one virtual memory of the memory manager
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub id: u8,
+    pub name: String,
+    pub entries: u64,
+    pub pages: u64, // Allocated wasm pages of 64 KiB
+    pub bytes: u64, // Approximate, allocated pages are not released
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct PublisherStats {
+    pub publisher: String, // Publisher anchor
+    pub usage: PublisherUsage,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub memories: Vec<MemoryStats>,
+    pub total_pages: u64,
+    pub total_bytes: u64,
+    pub stable_bytes: u64, // Including the memory manager header and unused buckets
+    pub publishers: Vec<PublisherStats>,
+}
+
+/// Pricing of the uploads by publishers, free if nothing is set
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct PaymentConfig {
+    pub cycles_per_byte: Option<u128>, // Pay by the attached cycles
+    pub icrc2: Option<TokenPrice>,     // Pay by the ICRC-2 approved transfer
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct TokenPrice {
+    pub ledger: Principal, // ICRC-2 ledger canister
+    pub tokens_per_byte: u128,
+}
+
+impl PaymentConfig {
+    pub fn is_free(&self) -> bool {
+        self.cycles_per_byte.is_none() && self.icrc2.is_none()
+    }
+}
+
+/// How the upload is paid
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum UploadPayment {
+    Free,
+    Cycles(u128),
+    Icrc2 { ledger: Principal, amount: u128, block: u64 },
+}
+
+/// Receipt of the upload by the publisher
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct UploadReceipt {
+    pub id: u64,
+    pub publisher: String, // Publisher anchor
+    pub anchor: String,    // Anchor of the uploaded item
+    pub caller: Principal,
+    pub bytes: u64,
+    pub payment: UploadPayment,
+    pub created: u64, // Nanoseconds
+    #[serde(default)]
+    pub error: Option<String>, // The upload is failed after the tokens are transferred
+    #[serde(default)]
+    pub refund: Option<u64>, // Block index of the refund transfer
+}
+
+/// Kinds of the stored entities
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum EntityKind {
+    Publisher,
+    Code,
+    Api,
+    Combined,
+    Dapp,
+}
+
+impl EntityKind {
+    fn code(&self) -> u8 {
+        match self {
+            EntityKind::Publisher => 0,
+            EntityKind::Code => 1,
+            EntityKind::Api => 2,
+            EntityKind::Combined => 3,
+            EntityKind::Dapp => 4,
+        }
+    }
+}
+
+/// The item uploaded by the publisher
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnedKey {
+    kind: u8,
+    item: Vec<u8>, // bytes of the key of the item
+}
+
+impl OwnedKey {
+    pub fn new(kind: EntityKind, item: Vec<u8>) -> Self {
+        Self {
+            kind: kind.code(),
+            item,
+        }
+    }
+    pub fn is(&self, kind: EntityKind) -> bool {
+        self.kind == kind.code()
+    }
+    pub fn item(&self) -> &[u8] {
+        &self.item
+    }
+}
+
+impl Storable for OwnedKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![self.kind];
+        bytes.extend_from_slice(&self.item);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            kind: bytes[0],
+            item: bytes[1..].to_vec(),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Counters that can be changed in batches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum CounterKind {
+    DappCalled,
+    DappAccessed,
+    DappCollected,
+    CombinedCalled,
+}
+
+impl CounterKind {
+    fn code(&self) -> u8 {
+        match self {
+            CounterKind::DappCalled => 0,
+            CounterKind::DappAccessed => 1,
+            CounterKind::DappCollected => 2,
+            CounterKind::CombinedCalled => 3,
+        }
+    }
+}
+
+/// Statistical period of the leaderboard, the current period if the index is not specified
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize)]
+pub enum RankPeriod {
+    Total,
+    Day(Option<u32>),  // Days since the unix epoch
+    Week(Option<u32>), // Weeks since the unix epoch
+}
+
+impl RankPeriod {
+    fn code(&self) -> u8 {
+        match self {
+            RankPeriod::Total => 0,
+            RankPeriod::Day(_) => 1,
+            RankPeriod::Week(_) => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct RankItem {
+    pub anchor: String,
+    pub count: u64,
+}
+
+/// Counter of the item in a certain period
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PeriodCounterKey {
+    kind: u8,
+    period: u8,
+    index: u32,
+    item: Vec<u8>,
+}
+
+impl PeriodCounterKey {
+    pub fn new(kind: CounterKind, period: RankPeriod, index: u32, item: Vec<u8>) -> Self {
+        Self {
+            kind: kind.code(),
+            period: period.code(),
+            index,
+            item,
+        }
+    }
+}
+
+impl Storable for PeriodCounterKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![self.kind, self.period];
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.item);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut index_bytes = [0_u8; 4];
+        index_bytes.copy_from_slice(&bytes[2..6]);
+        Self {
+            kind: bytes[0],
+            period: bytes[1],
+            index: u32::from_be_bytes(index_bytes),
+            item: bytes[6..].to_vec(),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Secondary index of the leaderboard, the larger count comes first
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RankKey {
+    kind: u8,
+    period: u8,
+    index: u32,
+    inverted: u64, // u64::MAX - count
+    pub item: Vec<u8>,
+}
+
+impl RankKey {
+    pub fn new(kind: CounterKind, period: RankPeriod, index: u32, count: u64, item: Vec<u8>) -> Self {
+        Self {
+            kind: kind.code(),
+            period: period.code(),
+            index,
+            inverted: u64::MAX - count,
+            item,
+        }
+    }
+    /// The first key of the leaderboard
+    pub fn first(kind: CounterKind, period: RankPeriod, index: u32) -> Self {
+        Self::new(kind, period, index, u64::MAX, vec![])
+    }
+    pub fn count(&self) -> u64 {
+        u64::MAX - self.inverted
+    }
+    pub fn is_same_board(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.period == other.period && self.index == other.index
+    }
+}
+
+impl Storable for RankKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![self.kind, self.period];
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.inverted.to_be_bytes());
+        bytes.extend_from_slice(&self.item);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut index_bytes = [0_u8; 4];
+        index_bytes.copy_from_slice(&bytes[2..6]);
+        let mut inverted_bytes = [0_u8; 8];
+        inverted_bytes.copy_from_slice(&bytes[6..14]);
+        Self {
+            kind: bytes[0],
+            period: bytes[1],
+            index: u32::from_be_bytes(index_bytes),
+            inverted: u64::from_be_bytes(inverted_bytes),
+            item: bytes[14..].to_vec(),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Moderation record of the frozen dapp
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct DappFrozen {
+    pub anchor: String,
+    pub moderator: Principal,
+    pub reason: String,
+    pub frozen: u64, // Nanoseconds
+}
+
+/// Access rules checked by the canister, besides the DappAccess of the dapp
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct DappAccessRules {
+    pub balance: Option<BalanceRequirement>, // ICRC-1 balance of the caller
+    #[serde(default)]
+    pub allow: Option<Vec<Principal>>, // Only these principals can access if set
+    #[serde(default)]
+    pub deny: Vec<Principal>, // These principals can not access
+    #[serde(default)]
+    pub issuer: Option<String>, // Publisher anchor, the signed token issued by it is required if set
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct BalanceRequirement {
+    pub ledger: Principal, // ICRC-1 ledger canister
+    pub min: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum KeyScheme {
+    Ed25519,   // 32 bytes public key
+    Secp256k1, // SEC1 encoded public key, the message is hashed by sha256 and the signature must be low-S
+}
+
+/// Public key registered by the publisher to sign access tokens
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct PublisherKey {
+    pub scheme: KeyScheme,
+    pub key: Vec<u8>,
+}
+
+/// Short-lived access token signed by the publisher off chain
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct SignedToken {
+    pub dapp: String, // anchor of the dapp
+    pub caller: Principal,
+    pub expires: u64, // Nanoseconds
+    pub nonce: u64,
+    pub signature: Vec<u8>,
+}
+
+/// Used nonce of the signed token, removed after it expires
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TokenNonceKey {
+    pub expires: u64,
+    dapp: [u8; 12], // bytes of WrappedDappId, the nonces of dapps are not shared
+    pub nonce: u64,
+    pub caller: Principal,
+}
+
+impl TokenNonceKey {
+    pub fn new(expires: u64, dapp: &WrappedDappId, nonce: u64, caller: Principal) -> Self {
+        let mut bytes = [0_u8; 12];
+        bytes.copy_from_slice(&dapp.to_bytes());
+        Self {
+            expires,
+            dapp: bytes,
+            nonce,
+            caller,
+        }
+    }
+    /// The first key that expires at the time
+    pub fn first(expires: u64) -> Self {
+        Self {
+            expires,
+            dapp: [0_u8; 12],
+            nonce: 0,
+            caller: Principal::management_canister(),
+        }
+    }
+}
+
+impl Storable for TokenNonceKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let caller = self.caller.as_slice();
+        let mut bytes = [0_u8; 58];
+        bytes[..8].copy_from_slice(&self.expires.to_be_bytes());
+        bytes[8..20].copy_from_slice(&self.dapp);
+        bytes[20..28].copy_from_slice(&self.nonce.to_be_bytes());
+        bytes[28] = caller.len() as u8;
+        bytes[29..29 + caller.len()].copy_from_slice(caller);
+        Cow::Owned(bytes.to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut expires = [0_u8; 8];
+        expires.copy_from_slice(&bytes[..8]);
+        let mut dapp = [0_u8; 12];
+        dapp.copy_from_slice(&bytes[8..20]);
+        let mut nonce = [0_u8; 8];
+        nonce.copy_from_slice(&bytes[20..28]);
+        let len = bytes[28] as usize;
+        Self {
+            expires: u64::from_be_bytes(expires),
+            dapp,
+            nonce: u64::from_be_bytes(nonce),
+            caller: Principal::from_slice(&bytes[29..29 + len]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 58,
+        is_fixed_size: true,
+    };
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct WrappedDappId(pub DappId, Option<u32>);
 
@@ -31,6 +575,12 @@ impl From<DappParsedId> for WrappedDappId {
     }
 }
 
+impl From<&DappParsedId> for WrappedDappId {
+    fn from(id: &DappParsedId) -> Self {
+        Self(id.id.clone(), id.nonce)
+    }
+}
+
 impl Storable for WrappedDappId {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         let mut bytes = [0_u8; 12];
@@ -54,3 +604,446 @@ impl Storable for WrappedDappId {
         is_fixed_size: true,
     };
 }
+
+/// Statistics of the dapp on a certain day
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DappDayKey(pub WrappedDappId, pub u32);
+
+impl Storable for DappDayKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.0.to_bytes().to_vec();
+        bytes.extend_from_slice(&self.1.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let dapp_id = WrappedDappId::from_bytes(Cow::Borrowed(&bytes[..12]));
+        let mut day_bytes = [0_u8; 4];
+        day_bytes.copy_from_slice(&bytes[12..]);
+        Self(dapp_id, u32::from_be_bytes(day_bytes))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
+
+const UNIQUE_USERS_PRECISION: u32 = 10; // 1024 registers, the standard error is about 3.25%
+const UNIQUE_USERS_REGISTERS: usize = 1 << UNIQUE_USERS_PRECISION;
+
+/// HyperLogLog sketch, estimate the number of distinct callers
+#[derive(Debug, Clone)]
+pub struct UniqueUsers {
+    registers: Vec<u8>,
+}
+
+impl Default for UniqueUsers {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; UNIQUE_USERS_REGISTERS],
+        }
+    }
+}
+
+impl UniqueUsers {
+    pub fn insert(&mut self, user: &Principal) {
+        let hash = hash_principal(user);
+        let index = (hash >> (64 - UNIQUE_USERS_PRECISION)) as usize;
+        let rank = ((hash << UNIQUE_USERS_PRECISION).leading_zeros() + 1).min(64 - UNIQUE_USERS_PRECISION + 1) as u8;
+        if self.registers[index] < rank {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *register < *other {
+                *register = *other;
+            }
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = UNIQUE_USERS_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2_f64.powi(-(*r as i32))).sum();
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        let estimate = alpha * m * m / sum;
+        // Small range correction
+        if estimate <= 2.5 * m && 0 < zeros {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+impl Storable for UniqueUsers {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Borrowed(&self.registers)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            registers: bytes.to_vec(),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: UNIQUE_USERS_REGISTERS as u32,
+        is_fixed_size: true,
+    };
+}
+
+/// The hash must be stable across upgrades, so do not use the hasher of std
+fn hash_principal(user: &Principal) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in user.as_slice() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // fmix64 of MurmurHash3, spread the bits
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+/// The dapp collected by the user
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserCollectionKey {
+    pub user: Principal,
+    dapp: [u8; 12], // bytes of WrappedDappId
+}
+
+impl UserCollectionKey {
+    pub fn new(user: Principal, dapp: &WrappedDappId) -> Self {
+        let mut bytes = [0_u8; 12];
+        bytes.copy_from_slice(&dapp.to_bytes());
+        Self { user, dapp: bytes }
+    }
+    /// The first key of the user
+    pub fn first(user: Principal) -> Self {
+        Self { user, dapp: [0_u8; 12] }
+    }
+    pub fn dapp(&self) -> WrappedDappId {
+        WrappedDappId::from_bytes(Cow::Borrowed(&self.dapp))
+    }
+}
+
+impl Storable for UserCollectionKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let user = self.user.as_slice();
+        let mut bytes = [0_u8; 42];
+        bytes[0] = user.len() as u8;
+        bytes[1..1 + user.len()].copy_from_slice(user);
+        bytes[30..].copy_from_slice(&self.dapp);
+        Cow::Owned(bytes.to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let user = Principal::from_slice(&bytes[1..1 + len]);
+        let mut dapp = [0_u8; 12];
+        dapp.copy_from_slice(&bytes[30..]);
+        Self { user, dapp }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 42,
+        is_fixed_size: true,
+    };
+}
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// One page of the exported snapshot, encoded by CBOR.
+/// Keys and values are the Storable encodings of the maps, so the snapshot is restored byte by byte
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotPage {
+    pub version: u32,
+    pub page: u64,
+    pub manifest: Option<SnapshotManifest>, // Only in the first page
+    pub records: Vec<SnapshotRecord>,
+    pub last: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub canister: Principal,
+    pub created: u64, // Nanoseconds
+    pub maps: Vec<SnapshotMapInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotMapInfo {
+    pub id: u8, // MemoryId of the map
+    pub name: String,
+    pub entries: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRecord {
+    pub map: u8, // MemoryId of the map
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>, // Empty for cells
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+}
+
+/// Position of the next page, returned by the last page
+#[derive(Debug, Clone, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct ExportCursor {
+    pub page: u64,
+    pub map: u8,
+    pub after: Option<Vec<u8>>, // The last exported key of the map
+    pub checksum: Vec<u8>,      // Checksum of the last page
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ExportPage {
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>, // CBOR of SnapshotPage
+    #[serde(with = "serde_bytes")]
+    pub checksum: Vec<u8>, // sha256(checksum of the last page + data)
+    pub next: Option<ExportCursor>,
+}
+
+/// Progress of the export, the writes are rejected until the last page is exported
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct ExportProgress {
+    pub exporting: bool,
+    pub created: u64,               // Nanoseconds
+    pub next: Option<ExportCursor>, // Cursor of the next page
+    pub last: Option<ExportCursor>, // Cursor of the last exported page, it can be exported again
+}
+
+/// Checksum of the page, chained with the last page so that missing or reordered pages are found
+pub fn snapshot_checksum(last: &[u8], data: &[u8]) -> Vec<u8> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(last);
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+/// Progress of the import, the canister is not live until the import is committed
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct ImportProgress {
+    pub importing: bool,
+    pub page: u64,                   // The next page
+    pub checksum: Vec<u8>,           // Checksum of the last imported page
+    pub finished: bool,              // The last page is imported
+    pub expected: Vec<(u8, u64)>,    // Entries of every map in the manifest
+    pub canister: Option<Principal>, // Canister in the manifest
+}
+
+/// Anchors in the reference fields of the model
+#[derive(Debug, Clone, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct ItemReferences {
+    pub code_anchors: Vec<String>,
+    pub apis_anchors: Vec<String>,
+    pub combined_anchors: Vec<String>,
+}
+
+/// Dapp for other canisters
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct DappRecord {
+    pub anchor: String,
+    pub publisher: Option<String>, // Anchor of the publisher who uploaded the dapp
+    pub name: String,
+    pub icon: String,
+    pub description: String,
+    pub category: String,
+    pub balance: Option<BalanceRequirement>, // Checked by the caller, the allow and deny lists are private
+    pub issuer: Option<String>,              // Publisher anchor, the signed token issued by it is required if set
+    pub accessed: u64,
+    pub called: u64,
+    pub collected: u64,
+    pub references: ItemReferences,
+}
+
+/// Combined for other canisters
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct CombinedRecord {
+    pub anchor: String,
+    pub called: u64,
+    pub references: ItemReferences,
+}
+
+/// Uploaded item found by the anchor, the model is carried as JSON
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct AnchorItem {
+    pub anchor: String,
+    pub kind: EntityKind,
+    pub json: String,
+}
+
+/// The dapp and the items it depends on
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ResolvedDapp {
+    pub items: Vec<AnchorItem>,      // The dapp is the first item of the first page
+    pub missing: Vec<String>,        // Referenced anchors of this page not found in this canister
+    pub next: Option<ResolveCursor>, // Pass it back for the next page if there are more items
+}
+
+/// Rest of the traversal of the closure of the dapp
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ResolveCursor {
+    pub queue: Vec<String>, // Anchors to resolve in order
+    pub seen: Vec<String>,  // Anchors resolved or missing
+}
+
+/// Item of the anchor tagged by its kind, the models are carried as JSON like AnchorItem
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum AnchorValue {
+    Publisher(String),
+    Code(String),
+    Api(String),
+    Combined(String),
+    Dapp(String),
+}
+
+/// What is changed of the item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum ChangeAction {
+    Insert,
+    Update,
+    Delete, // Reserved, the items are not deleted by now
+    Freeze,
+    Unfreeze,
+    CounterFlush, // The counters are changed since the last flush
+}
+
+/// Event of the change feed
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    pub kind: EntityKind,
+    pub anchor: String,
+    pub action: ChangeAction,
+    pub timestamp: TimestampMills,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ChangesPage {
+    pub events: Vec<ChangeEvent>,
+    pub first: u64, // The oldest kept event, the events before it are pruned
+    pub next: u64,  // Continue from it
+}
+
+/// Settings of the index canister
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct IndexSettings {
+    pub max_stable_bytes: u64,       // The shard is full if crossed
+    pub cycles: u128,                // Cycles of the new shard
+    pub controllers: Vec<Principal>, // Controllers of the new shards besides the index
+    pub admins: Vec<Principal>,      // Administrators of the new shards besides the index
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        Self {
+            max_stable_bytes: 32 * 1024 * 1024 * 1024, // 32 GiB
+            cycles: 2_000_000_000_000,
+            controllers: vec![],
+            admins: vec![],
+        }
+    }
+}
+
+/// Storage shard known by the index
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ShardInfo {
+    pub canister: Principal,
+    pub created: u64, // Nanoseconds
+    pub stable_bytes: u64,
+    pub uploads_allowed: bool,
+    pub updated: u64, // Refreshed time
+    pub error: Option<String>,
+    #[serde(default)]
+    pub installing: bool, // Spawned by the index, the wasm or the administrators are not installed yet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(index: u64) -> Principal {
+        Principal::from_slice(&index.to_be_bytes())
+    }
+
+    #[test]
+    fn test_unique_users_insert() {
+        let mut unique = UniqueUsers::default();
+        assert_eq!(unique.estimate(), 0);
+
+        unique.insert(&user(1));
+        assert_eq!(unique.estimate(), 1);
+        unique.insert(&user(1)); // The same user is counted once
+        assert_eq!(unique.estimate(), 1);
+
+        let restored = UniqueUsers::from_bytes(unique.to_bytes());
+        assert_eq!(restored.registers, unique.registers);
+    }
+
+    #[test]
+    fn test_unique_users_estimate() {
+        for count in [100_u64, 1_000, 10_000, 100_000] {
+            let mut unique = UniqueUsers::default();
+            for index in 0..count {
+                unique.insert(&user(index));
+            }
+            let error = (unique.estimate() as f64 - count as f64).abs() / count as f64;
+            // 3 times the standard error
+            assert!(error < 0.1, "count: {count} estimate: {}", unique.estimate());
+        }
+    }
+
+    #[test]
+    fn test_unique_users_merge() {
+        let mut first = UniqueUsers::default();
+        let mut second = UniqueUsers::default();
+        for index in 0..1_000 {
+            first.insert(&user(index));
+            second.insert(&user(index + 500)); // Half of the users are shared
+        }
+        first.merge(&second);
+        let error = (first.estimate() as f64 - 1_500_f64).abs() / 1_500_f64;
+        assert!(error < 0.1, "estimate: {}", first.estimate());
+    }
+
+    #[test]
+    fn test_rank_key() {
+        let key = |index: u32, count: u64, item: u8| {
+            RankKey::new(CounterKind::DappCalled, RankPeriod::Day(None), index, count, vec![item])
+        };
+
+        // The larger count comes first, the same count is ordered by the item
+        assert!(key(7, 10, 1) < key(7, 5, 1));
+        assert!(key(7, 5, 1) < key(7, 5, 2));
+        assert_eq!(key(7, 10, 1).count(), 10);
+
+        // The order of the bytes is the same as the order of the keys
+        let mut keys = vec![key(8, 1, 1), key(7, 0, 1), key(7, u64::MAX, 3), key(7, 5, 2)];
+        keys.sort();
+        let mut bytes: Vec<Vec<u8>> = keys.iter().map(|key| key.to_bytes().to_vec()).collect();
+        bytes.sort();
+        let restored: Vec<RankKey> = bytes.into_iter().map(|b| RankKey::from_bytes(Cow::Owned(b))).collect();
+        assert_eq!(restored, keys);
+
+        // The first key is before every key of the board and after the keys of the previous board
+        let first = RankKey::first(CounterKind::DappCalled, RankPeriod::Day(None), 7);
+        assert!(first <= key(7, u64::MAX, 0));
+        assert!(key(6, 0, u8::MAX) < first);
+        assert!(first.is_same_board(&key(7, 0, 1)));
+        assert!(!first.is_same_board(&key(8, u64::MAX, 1)));
+        let week = RankKey::first(CounterKind::DappCalled, RankPeriod::Week(None), 7);
+        assert!(!first.is_same_board(&week));
+        let accessed = RankKey::first(CounterKind::DappAccessed, RankPeriod::Day(None), 7);
+        assert!(!first.is_same_board(&accessed));
+    }
+}